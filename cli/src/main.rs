@@ -0,0 +1,289 @@
+/*!
+Builds the `msg` of `ft_transfer_call` to the lockup from a CSV of beneficiaries.
+
+    lockup-cli <beneficiaries.csv> --token <token_id> --start <ns> --end <ns> --period <ns>
+        [--merkle] [--register-accounts] [--chunk-size <n>]
+
+Each CSV line is `account_id,amount`, a header line is skipped. Rows are checked with the
+same rules as the lockup. When every amount is equal, the output is a regular task.
+Otherwise it is a draft task with the `append_beneficiaries` chunks to send before
+`activate_task`, or with `--merkle` a merkle task with the proof of every beneficiary.
+`--register-accounts` registers up to 4 accounts of a regular task along with it, larger
+lists and drafts get the `register_beneficiaries` batches to send once the task is created.
+Merkle tasks have no account list to register.
+*/
+use lockup::errors::TaskError;
+use lockup::internal::{validate_allocation, validate_task_args, TaskArgs};
+use lockup::merkle::{leaf_data, node_data};
+use lockup::{MAX_BATCH_REGISTRATIONS, MAX_REGISTERED_ACCOUNTS};
+use near_sdk::json_types::{Base58CryptoHash, ValidAccountId};
+use near_sdk::serde_json::{self, json, Value};
+use near_sdk::{AccountId, CryptoHash};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::convert::{TryFrom, TryInto};
+use std::process;
+
+const DEFAULT_CHUNK_SIZE: usize = 100;
+
+struct Options {
+    path: String,
+    token_id: AccountId,
+    start_time: u64,
+    end_time: u64,
+    vesting_period: u64,
+    merkle: bool,
+    register_accounts: bool,
+    chunk_size: usize,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut args = std::env::args().skip(1);
+    let mut path = None;
+    let mut token_id = None;
+    let (mut start_time, mut end_time, mut vesting_period) = (None, None, None);
+    let mut merkle = false;
+    let mut register_accounts = false;
+    let mut chunk_size = DEFAULT_CHUNK_SIZE;
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("missing value of {}", name));
+        match arg.as_str() {
+            "--token" => token_id = Some(value("--token")?),
+            "--start" => start_time = Some(parse_number(&value("--start")?)?),
+            "--end" => end_time = Some(parse_number(&value("--end")?)?),
+            "--period" => vesting_period = Some(parse_number(&value("--period")?)?),
+            "--chunk-size" => chunk_size = parse_number(&value("--chunk-size")?)?,
+            "--merkle" => merkle = true,
+            "--register-accounts" => register_accounts = true,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => path = Some(arg),
+        }
+    }
+    Ok(Options {
+        path: path.ok_or("missing beneficiaries csv")?,
+        token_id: token_id.ok_or("missing --token")?,
+        start_time: start_time.ok_or("missing --start")?,
+        end_time: end_time.ok_or("missing --end")?,
+        vesting_period: vesting_period.ok_or("missing --period")?,
+        merkle,
+        register_accounts,
+        chunk_size: chunk_size.max(1),
+    })
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value.trim().parse().map_err(|_| format!("invalid number {}", value))
+}
+
+fn read_beneficiaries(path: &str) -> Result<Vec<(AccountId, u128)>, String> {
+    let content = std::fs::read_to_string(path).map_err(|err| format!("failed to read {}: {}", path, err))?;
+    let mut beneficiaries = vec![];
+    for (line_number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut columns = line.split(',').map(|column| column.trim());
+        let (account_id, amount) = match (columns.next(), columns.next(), columns.next()) {
+            (Some(account_id), Some(amount), None) => (account_id, amount),
+            _ => return Err(format!("line {}: expected account_id,amount", line_number + 1)),
+        };
+        let amount: u128 = match amount.parse() {
+            Ok(amount) => amount,
+            Err(_) if line_number == 0 => continue,
+            Err(_) => return Err(format!("line {}: invalid amount {}", line_number + 1, amount)),
+        };
+        ValidAccountId::try_from(account_id)
+            .map_err(|_| format!("line {}: invalid account id {}", line_number + 1, account_id))?;
+        beneficiaries.push((account_id.to_string(), amount));
+    }
+    Ok(beneficiaries)
+}
+
+/// Same checks as `append_beneficiaries` on the lockup, for allocations of any amount.
+fn validate_beneficiaries(beneficiaries: &[(AccountId, u128)], claim_count: u64) -> Result<(), TaskError> {
+    let mut accounts = HashSet::new();
+    for (account_id, amount) in beneficiaries {
+        if !accounts.insert(account_id) {
+            return Err(TaskError::DuplicateAccount(account_id.clone()));
+        }
+        validate_allocation(&[account_id.clone()], *amount, claim_count, false)?;
+    }
+    Ok(())
+}
+
+fn sha256(data: &[u8]) -> CryptoHash {
+    Sha256::digest(data).as_slice().try_into().unwrap()
+}
+
+/// Merkle root of `leaves` and the proof of each of them. A node without a sibling
+/// is moved up a level as is.
+fn merkle_tree(leaves: Vec<CryptoHash>) -> (CryptoHash, Vec<Vec<CryptoHash>>) {
+    let mut proofs = vec![vec![]; leaves.len()];
+    let mut positions: Vec<usize> = (0..leaves.len()).collect();
+    let mut level = leaves;
+    while level.len() > 1 {
+        for (leaf, position) in positions.iter_mut().enumerate() {
+            let sibling = *position ^ 1;
+            if sibling < level.len() {
+                proofs[leaf].push(level[sibling]);
+            }
+            *position /= 2;
+        }
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => sha256(&node_data(left, right)),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    (level[0], proofs)
+}
+
+fn build(options: &Options, beneficiaries: Vec<(AccountId, u128)>) -> Result<Value, String> {
+    if beneficiaries.is_empty() {
+        return Err(TaskError::EmptyAccountList.to_string());
+    }
+    let total: u128 = beneficiaries.iter().map(|(_, amount)| amount).sum();
+    let equal = beneficiaries.iter().all(|(_, amount)| *amount == beneficiaries[0].1);
+    if options.register_accounts && options.merkle {
+        return Err("--register-accounts needs an account list, merkle tasks have none".to_string());
+    }
+    let register_with_task = options.register_accounts && equal && beneficiaries.len() <= MAX_REGISTERED_ACCOUNTS;
+    let mut task_args = json!({
+        "token_id": options.token_id,
+        "account_list": [],
+        "start_time": options.start_time.to_string(),
+        "end_time": options.end_time.to_string(),
+        "vesting_period": options.vesting_period.to_string(),
+        "amount": total.to_string(),
+        "register_accounts": register_with_task,
+    });
+    let mut output = json!({ "amount": total.to_string() });
+    if options.register_accounts && !register_with_task {
+        output["register_beneficiaries"] = beneficiaries
+            .chunks(MAX_BATCH_REGISTRATIONS)
+            .map(|chunk| chunk.iter().map(|(account_id, _)| account_id.clone()).collect::<Vec<AccountId>>())
+            .collect::<Vec<Vec<AccountId>>>()
+            .into();
+    }
+    if options.merkle {
+        let leaves = beneficiaries
+            .iter()
+            .map(|(account_id, amount)| sha256(&leaf_data(account_id, *amount)))
+            .collect();
+        let (root, proofs) = merkle_tree(leaves);
+        task_args["merkle_root"] = json!(Base58CryptoHash::from(root));
+        output["merkle_root"] = json!(Base58CryptoHash::from(root));
+        output["proofs"] = beneficiaries
+            .iter()
+            .zip(proofs)
+            .map(|((account_id, amount), proof)| {
+                let proof: Vec<Base58CryptoHash> = proof.into_iter().map(Base58CryptoHash::from).collect();
+                (account_id.clone(), json!({ "allocation": amount.to_string(), "proof": proof }))
+            })
+            .collect::<serde_json::Map<String, Value>>()
+            .into();
+    } else if equal {
+        let account_list: Vec<&AccountId> = beneficiaries.iter().map(|(account_id, _)| account_id).collect();
+        task_args["account_list"] = json!(account_list);
+    } else {
+        task_args["draft"] = json!(true);
+        output["append_beneficiaries"] = beneficiaries
+            .chunks(options.chunk_size)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .map(|(account_id, amount)| json!({ "account_id": account_id, "amount": amount.to_string() }))
+                    .collect::<Vec<Value>>()
+            })
+            .collect::<Vec<Vec<Value>>>()
+            .into();
+    }
+
+    let args: TaskArgs = serde_json::from_value(task_args.clone()).map_err(|err| err.to_string())?;
+    validate_task_args(&args, total).map_err(|err| err.to_string())?;
+    if options.merkle || !equal {
+        let claim_count = (options.end_time - options.start_time) / options.vesting_period;
+        validate_beneficiaries(&beneficiaries, claim_count).map_err(|err| err.to_string())?;
+    }
+    output["msg"] = json!(task_args.to_string());
+    Ok(output)
+}
+
+fn main() {
+    let result = parse_options().and_then(|options| {
+        let beneficiaries = read_beneficiaries(&options.path)?;
+        build(&options, beneficiaries)
+    });
+    match result {
+        Ok(output) => println!("{}", serde_json::to_string_pretty(&output).unwrap()),
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lockup::merkle::{leaf_hash, verify_proof};
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+
+    fn options(merkle: bool, register_accounts: bool) -> Options {
+        Options {
+            path: String::new(),
+            token_id: "token.near".to_string(),
+            start_time: 0,
+            end_time: 1000,
+            vesting_period: 100,
+            merkle,
+            register_accounts,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+
+    fn beneficiaries(count: usize) -> Vec<(AccountId, u128)> {
+        (0..count).map(|i| (format!("account{}.near", i), 1000 + i as u128)).collect()
+    }
+
+    #[test]
+    fn every_proof_verifies() {
+        // the lockup hashes with the host function
+        testing_env!(VMContextBuilder::new().build());
+        for count in 1..=9 {
+            let beneficiaries = beneficiaries(count);
+            let leaves = beneficiaries.iter().map(|(account_id, amount)| sha256(&leaf_data(account_id, *amount))).collect();
+            let (root, proofs) = merkle_tree(leaves);
+            for ((account_id, amount), proof) in beneficiaries.iter().zip(proofs) {
+                assert!(verify_proof(&root, leaf_hash(account_id, *amount), &proof), "proof of {} in a tree of {}", account_id, count);
+                assert!(!verify_proof(&root, leaf_hash(account_id, *amount + 1), &proof));
+            }
+        }
+    }
+
+    #[test]
+    fn register_accounts_needs_account_list() {
+        assert!(build(&options(true, true), beneficiaries(3)).is_err());
+        assert!(build(&options(true, false), beneficiaries(3)).is_ok());
+        let equal = vec![("alice.near".to_string(), 1000), ("bob.near".to_string(), 1000)];
+        let output = build(&options(false, true), equal).unwrap();
+        let task_args: Value = serde_json::from_str(output["msg"].as_str().unwrap()).unwrap();
+        assert_eq!(task_args["register_accounts"], true);
+    }
+
+    #[test]
+    fn large_lists_are_registered_in_batches() {
+        let output = build(&options(false, true), beneficiaries(8)).unwrap();
+        let task_args: Value = serde_json::from_str(output["msg"].as_str().unwrap()).unwrap();
+        assert_eq!(task_args["register_accounts"], false);
+        let batches = output["register_beneficiaries"].as_array().unwrap();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].as_array().unwrap().len(), MAX_BATCH_REGISTRATIONS);
+        assert_eq!(batches[1][1], "account7.near");
+    }
+}
@@ -0,0 +1,103 @@
+use crate::*;
+use std::fmt;
+
+/// Reasons a task is rejected. Codes are stable and safe for clients to match on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TaskError {
+    InvalidMessage,
+    NotCreator,
+    TokenNotMatch,
+    TokenNotRegistered,
+    NotEnoughBalance,
+    ZeroAmount,
+    InvalidTimeRange,
+    ZeroVestingPeriod,
+    VestingPeriodTooLong,
+    EmptyAccountList,
+    DuplicateAccount(AccountId),
+    ZeroClaimAmount,
+    TaskNotFound,
+    TaskArchived,
+    DraftWithAccounts,
+    MerkleWithAccounts,
+    CreatorNotRegistered,
+    AcceptanceDeadlineRequired,
+    MerkleWithAcceptance,
+    StakingNotSupported,
+    StakingTaskLocked,
+    LegsTaskLocked,
+    InvalidLeg,
+    ClaimDeadlineTooEarly,
+    TooManyRegistrations,
+    MerkleTaskLocked,
+    UnknownStake,
+}
+
+impl TaskError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            TaskError::InvalidMessage => "E001",
+            TaskError::NotCreator => "E002",
+            TaskError::TokenNotMatch => "E003",
+            TaskError::TokenNotRegistered => "E004",
+            TaskError::NotEnoughBalance => "E005",
+            TaskError::ZeroAmount => "E006",
+            TaskError::InvalidTimeRange => "E007",
+            TaskError::ZeroVestingPeriod => "E008",
+            TaskError::VestingPeriodTooLong => "E009",
+            TaskError::EmptyAccountList => "E010",
+            TaskError::DuplicateAccount(_) => "E011",
+            TaskError::ZeroClaimAmount => "E012",
+            TaskError::TaskNotFound => "E013",
+            TaskError::TaskArchived => "E014",
+            TaskError::DraftWithAccounts => "E015",
+            TaskError::MerkleWithAccounts => "E016",
+            TaskError::CreatorNotRegistered => "E017",
+            TaskError::AcceptanceDeadlineRequired => "E018",
+            TaskError::MerkleWithAcceptance => "E019",
+            TaskError::StakingNotSupported => "E020",
+            TaskError::StakingTaskLocked => "E021",
+            TaskError::LegsTaskLocked => "E022",
+            TaskError::InvalidLeg => "E023",
+            TaskError::ClaimDeadlineTooEarly => "E024",
+            TaskError::TooManyRegistrations => "E025",
+            TaskError::MerkleTaskLocked => "E026",
+            TaskError::UnknownStake => "E027",
+        }
+    }
+}
+
+impl fmt::Display for TaskError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ", self.code())?;
+        match self {
+            TaskError::InvalidMessage => write!(f, "msg is not valid task arguments"),
+            TaskError::NotCreator => write!(f, "task creator only"),
+            TaskError::TokenNotMatch => write!(f, "token not match"),
+            TaskError::TokenNotRegistered => write!(f, "token not registered"),
+            TaskError::NotEnoughBalance => write!(f, "not enough balance"),
+            TaskError::ZeroAmount => write!(f, "amount should be greater than 0"),
+            TaskError::InvalidTimeRange => write!(f, "end time should be larger than start time"),
+            TaskError::ZeroVestingPeriod => write!(f, "vesting period should be greater than 0"),
+            TaskError::VestingPeriodTooLong => write!(f, "total duration must be larger than a single vesting period"),
+            TaskError::EmptyAccountList => write!(f, "list length should greater than 0"),
+            TaskError::DuplicateAccount(account_id) => write!(f, "duplicate account {}", account_id),
+            TaskError::ZeroClaimAmount => write!(f, "amount is too small to be claimed in every vesting period"),
+            TaskError::TaskNotFound => write!(f, "task not exist"),
+            TaskError::TaskArchived => write!(f, "task archived"),
+            TaskError::DraftWithAccounts => write!(f, "draft task should be created without accounts, append them in chunks"),
+            TaskError::MerkleWithAccounts => write!(f, "merkle task should be created without accounts"),
+            TaskError::CreatorNotRegistered => write!(f, "creator should register with storage_deposit first"),
+            TaskError::AcceptanceDeadlineRequired => write!(f, "task requiring acceptance should have an acceptance deadline"),
+            TaskError::MerkleWithAcceptance => write!(f, "merkle task can't require acceptance, claims are accepted by proof"),
+            TaskError::StakingNotSupported => write!(f, "staking task should be created with its accounts, without draft, merkle root, acceptance or claim deadline"),
+            TaskError::StakingTaskLocked => write!(f, "allocations of a staking task can't be changed"),
+            TaskError::LegsTaskLocked => write!(f, "allocations of a task with legs can't be changed"),
+            TaskError::InvalidLeg => write!(f, "leg should be a token the task doesn't hold yet, on a task not completed"),
+            TaskError::ClaimDeadlineTooEarly => write!(f, "claim deadline should be after end time"),
+            TaskError::TooManyRegistrations => write!(f, "at most {} accounts can be registered with the token along with a task", MAX_REGISTERED_ACCOUNTS),
+            TaskError::MerkleTaskLocked => write!(f, "allocations of a merkle task can't be changed, they are fixed by its root"),
+            TaskError::UnknownStake => write!(f, "no stake of this token held with the sender under this key"),
+        }
+    }
+}
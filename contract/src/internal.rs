@@ -2,7 +2,11 @@
 
 
 use crate::*;
-use near_sdk::{PromiseOrValue, PromiseResult, json_types::ValidAccountId, serde_json};
+use near_sdk::{PromiseOrValue, PromiseResult, StorageUsage, json_types::ValidAccountId, serde_json};
+use std::collections::HashSet;
+use crate::storage::TOKEN_STORAGE_USAGE;
+use crate::utils::{legs_settled, vested_amount};
+use crate::view::{Schedule, SchedulePeriod, Unlock};
 
 #[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
 #[serde(crate = "near_sdk::serde")]
@@ -13,105 +17,985 @@ pub struct TaskArgs {
     start_time: U64, 
     end_time: U64, 
     vesting_period: U64, 
-    amount: U128
+    amount: U128,
+    register_accounts: Option<bool>,
+    merge_duplicates: Option<bool>,
+    draft: Option<bool>,
+    merkle_root: Option<Base58CryptoHash>,
+    requires_acceptance: Option<bool>,
+    acceptance_deadline: Option<U64>,
+    staking_pool: Option<AccountId>,
+    claim_deadline: Option<U64>,
+    start_on_tge: Option<bool>,
+    voting: Option<bool>
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(Debug, Clone)]
+pub struct Beneficiary {
+    account_id: AccountId,
+    amount: U128,
+}
+
+/// `msg` of `ft_transfer_call` to this contract.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(untagged)]
+pub enum TokenReceiverMessage {
+    AddTask(TaskArgs),
+    AddBeneficiaries {
+        index: u32,
+        account_list: Vec<AccountId>,
+        amount: U128,
+        register_accounts: Option<bool>
+    },
+    TopUp {
+        index: u32,
+        account_id: AccountId,
+        amount: U128
+    },
+    AddLeg {
+        index: u32,
+        amount: U128
+    },
+    /// Sent by a staking pool with what it returns of the stake of task `stake_key`.
+    Unstaked {
+        stake_key: String
+    },
+}
+
+impl Token {
+    pub(crate) fn new(token_id: &AccountId, metadata: Option<TokenMetadata>, storage_balance_min: u128) -> Self {
+        Self {
+            metadata,
+            storage_balance_min,
+            live_tasks: 0,
+            tasks: UnorderedSet::new(StorageKey::TokenTasks { token_hash: env::sha256(token_id.as_bytes()).try_into().unwrap() }),
+            amount: 0,
+            amount_left: 0,
+            tge: None,
+        }
+    }
+}
+
+impl Task {
+    /// Moves the schedule of a task waiting for the TGE to begin at `start_time`.
+    pub(crate) fn start(&mut self, start_time: u64) {
+        self.end_time = start_time + (self.end_time - PENDING_START_TIME);
+        self.claim_deadline = self.claim_deadline.map(|deadline| start_time + (deadline - PENDING_START_TIME));
+        self.start_time = start_time;
+        self.awaiting_tge = false;
+    }
+
+    /// Splits `amount` equally between `account_list`, adding to the allocation of
+    /// accounts listed more than once. New accounts vest from `base_time`. Returns the
+    /// amount allocated, which is `amount` less the rounding remainder.
+    pub(crate) fn allocate(&mut self, account_list: &[AccountId], amount: u128, base_time: u64) -> u128 {
+        let share = amount / account_list.len() as u128;
+        for account_id in account_list {
+            let mut claim_info = self.accounts.get(account_id).unwrap_or(ClaimInfo {
+                amount: 0,
+                amount_left: 0,
+                base_amount: 0,
+                base_time: base_time,
+                claim_time: base_time,
+                transfer_failed: false,
+                accepted: self.acceptance_deadline.is_none(),
+                rewards: 0,
+                reward_debt: 0,
+                legs_claimed: vec![],
+                claims_in_flight: 0,
+                storage_paid: 0
+            });
+            claim_info.amount += share;
+            claim_info.amount_left += share;
+            self.insert_account(account_id, &claim_info);
+        }
+        let allocated = share * account_list.len() as u128;
+        self.amount_left += allocated;
+        allocated
+    }
+
+    pub(crate) fn insert_account(&mut self, account_id: &AccountId, claim_info: &ClaimInfo) {
+        if self.accounts.insert(account_id, claim_info).is_none() {
+            self.account_count += 1;
+        }
+    }
+
+    pub(crate) fn remove_account(&mut self, account_id: &AccountId) {
+        if self.accounts.remove(account_id).is_some() {
+            self.account_count -= 1;
+        }
+    }
+}
+
+/// Checks the list of accounts to allocate `amount` to, over `claim_count` vesting periods.
+pub fn validate_allocation(account_list: &[AccountId], amount: u128, claim_count: u64, merge_duplicates: bool) -> Result<(), TaskError> {
+    if account_list.is_empty() {
+        return Err(TaskError::EmptyAccountList);
+    }
+    if !merge_duplicates {
+        let mut accounts = HashSet::new();
+        for account_id in account_list.iter() {
+            if !accounts.insert(account_id) {
+                return Err(TaskError::DuplicateAccount(account_id.clone()));
+            }
+        }
+    }
+    let single_account_amount = amount / account_list.len() as u128;
+    if single_account_amount / claim_count as u128 == 0 {
+        return Err(TaskError::ZeroClaimAmount);
+    }
+    Ok(())
+}
+
+/// Checks `task_args` for a task funded with `token_amount`, everything but the
+/// token registration. Also usable off-chain.
+pub fn validate_task_args(task_args: &TaskArgs, token_amount: u128) -> Result<(), TaskError> {
+    let start_time: u64 = task_args.start_time.into();
+    let end_time: u64 = task_args.end_time.into();
+    let amount: u128 = task_args.amount.into();
+    let vesting_period: u64 = task_args.vesting_period.into();
+    if amount == 0 {
+        return Err(TaskError::ZeroAmount);
+    }
+    if token_amount < amount {
+        return Err(TaskError::NotEnoughBalance);
+    }
+    if start_time >= end_time {
+        return Err(TaskError::InvalidTimeRange);
+    }
+    if vesting_period == 0 {
+        return Err(TaskError::ZeroVestingPeriod);
+    }
+    if end_time - start_time < vesting_period {
+        return Err(TaskError::VestingPeriodTooLong);
+    }
+    if task_args.claim_deadline.map_or(false, |deadline| u64::from(deadline) <= end_time) {
+        return Err(TaskError::ClaimDeadlineTooEarly);
+    }
+    if task_args.register_accounts.unwrap_or(false) && task_args.account_list.len() > MAX_REGISTERED_ACCOUNTS {
+        return Err(TaskError::TooManyRegistrations);
+    }
+    if task_args.requires_acceptance.unwrap_or(false) {
+        if task_args.acceptance_deadline.is_none() {
+            return Err(TaskError::AcceptanceDeadlineRequired);
+        }
+        if task_args.merkle_root.is_some() {
+            return Err(TaskError::MerkleWithAcceptance);
+        }
+    }
+    if task_args.staking_pool.is_some() && (task_args.merkle_root.is_some() || task_args.draft.unwrap_or(false) || task_args.requires_acceptance.unwrap_or(false) || task_args.claim_deadline.is_some()) {
+        return Err(TaskError::StakingNotSupported);
+    }
+    if task_args.merkle_root.is_some() {
+        if !task_args.account_list.is_empty() {
+            return Err(TaskError::MerkleWithAccounts);
+        }
+        return Ok(());
+    }
+    if task_args.draft.unwrap_or(false) {
+        if !task_args.account_list.is_empty() {
+            return Err(TaskError::DraftWithAccounts);
+        }
+        return Ok(());
+    }
+    let claim_count = (end_time - start_time) / vesting_period;
+    validate_allocation(&task_args.account_list, amount, claim_count, task_args.merge_duplicates.unwrap_or(false))
+}
+
+/// Unlock timeline of the task `task_args` would create: the amount each beneficiary
+/// can claim from each vesting period on, computed like `get_claim_amount`. Allocations
+/// of draft and merkle tasks are not known yet, their periods only have the task total.
+pub fn preview_schedule(task_args: &TaskArgs) -> Result<Schedule, TaskError> {
+    let amount: u128 = task_args.amount.into();
+    validate_task_args(task_args, amount)?;
+    let start_time: u64 = task_args.start_time.into();
+    let end_time: u64 = task_args.end_time.into();
+    let vesting_period: u64 = task_args.vesting_period.into();
+
+    let mut allocations: Vec<(AccountId, u128)> = vec![];
+    let mut remainder = 0;
+    if !task_args.account_list.is_empty() {
+        let share = amount / task_args.account_list.len() as u128;
+        for account_id in task_args.account_list.iter() {
+            match allocations.iter_mut().find(|(allocated_id, _)| allocated_id == account_id) {
+                Some((_, allocation)) => *allocation += share,
+                None => allocations.push((account_id.clone(), share)),
+            }
+        }
+        remainder = amount - share * task_args.account_list.len() as u128;
+    }
+
+    let claim_count = (end_time - start_time) / vesting_period;
+    let vested = |allocation: u128, timestamp: u64| vested_amount(allocation, 0, start_time, end_time, vesting_period, timestamp);
+    let periods = (1..=claim_count).map(|period| {
+        let timestamp = start_time + period * vesting_period;
+        let unlocks: Vec<Unlock> = allocations.iter().map(|(account_id, allocation)| Unlock {
+            account_id: account_id.clone(),
+            amount: (vested(*allocation, timestamp) - vested(*allocation, timestamp - vesting_period)).into(),
+        }).collect();
+        let period_amount = if unlocks.is_empty() {
+            vested(amount, timestamp) - vested(amount, timestamp - vesting_period)
+        } else {
+            unlocks.iter().map(|unlock| u128::from(unlock.amount)).sum()
+        };
+        SchedulePeriod {
+            timestamp: timestamp.into(),
+            amount: period_amount.into(),
+            unlocks,
+        }
+    }).collect();
+    Ok(Schedule {
+        periods,
+        remainder: remainder.into(),
+    })
 }
 
 impl Lockup {
 
-    pub(crate) fn internal_add_task(&mut self, task_args: TaskArgs, token_amount: u128) -> U128{
+    pub(crate) fn internal_validate_task(&self, task_args: &TaskArgs, token_amount: u128) -> Result<(), TaskError> {
+        if self.tokens.get(&task_args.token_id).is_none() {
+            return Err(TaskError::TokenNotRegistered);
+        }
+        validate_task_args(task_args, token_amount)
+    }
+
+    pub(crate) fn internal_add_task(&mut self, task_args: TaskArgs, creator_id: &AccountId, token_amount: u128) -> Result<U128, TaskError> {
+        self.internal_validate_task(&task_args, token_amount)?;
         let mut token_amount = token_amount;
-        let start_time: u64 = task_args.start_time.into();
-        let end_time: u64 = task_args.end_time.into();
+        let awaiting_tge = task_args.start_on_tge.unwrap_or(false);
+        // a task waiting for the TGE only takes the length of its schedule from the arguments
+        let shift = |timestamp: u64| if awaiting_tge { PENDING_START_TIME + (timestamp - u64::from(task_args.start_time)) } else { timestamp };
+        let start_time: u64 = shift(task_args.start_time.into());
+        let end_time: u64 = shift(task_args.end_time.into());
         let amount: u128 = task_args.amount.into();
         let vesting_period: u64 = task_args.vesting_period.into();
-        assert!(token_amount >= amount, "not enough balance");
-        assert!(start_time < end_time, "start time should larger than end time");
-        assert!(end_time - start_time >= vesting_period, "total duration must be larger than a single vesting period");
-        assert!(task_args.account_list.len() > 0, "list length should greater than 0");
-        let single_account_amount = amount / task_args.account_list.len() as u128;
-        let claim_count = ((end_time - start_time) / vesting_period) as u128;
-        let single_claim_amount = single_account_amount / claim_count;
+        let mut token = self.tokens.get(&task_args.token_id).unwrap();
         
-        let key_prefix = task_args.token_id.clone() + &self.tasks.len().to_string();
-        let mut accounts = LookupMap::new(key_prefix.into_bytes());
-        for account in task_args.account_list {
-            accounts.insert(&account, &ClaimInfo {
-                amount_left: single_account_amount,
-                claim_time: start_time
-            });
-        }
-        token_amount -= amount;
-        self.tasks.push(&Task { 
+        let mut task = Task { 
+            creator_id: creator_id.clone(),
             token_id: task_args.token_id.clone(), 
-            accounts: accounts, 
+            accounts: LookupMap::new(StorageKey::TaskAccounts { index: self.tasks.len() }), 
             start_time: start_time.into(), 
             end_time: end_time.into(), 
             vesting_period: vesting_period.into(), 
             amount: amount.into(),
-            single_claim_amount: single_claim_amount,
+            amount_left: 0,
+            status: if task_args.draft.unwrap_or(false) { TaskStatus::Draft } else { TaskStatus::Active },
+            extensions: vec![],
+            merkle_root: task_args.merkle_root.map(|merkle_root| merkle_root.into()),
+            acceptance_deadline: if task_args.requires_acceptance.unwrap_or(false) {
+                task_args.acceptance_deadline.map(|deadline| deadline.into())
+            } else {
+                None
+            },
+            staking: None,
+            legs: vec![],
+            claim_deadline: task_args.claim_deadline.map(|deadline| shift(deadline.into())),
+            awaiting_tge,
+            account_count: 0,
+            voting: task_args.voting.unwrap_or(false),
+        };
+        if task.merkle_root.is_some() {
+            // allocations are only known when claimed, they add up to `amount`
+            task.amount_left = amount;
+        } else if !task_args.account_list.is_empty() {
+            task.amount = task.allocate(&task_args.account_list, amount, start_time);
+        }
+        task.staking = task_args.staking_pool.map(|pool_id| Staking::new(pool_id, task.amount_left));
+        let index = self.tasks.len() as u32;
+        for account_id in task_args.account_list.iter() {
+            self.internal_add_position(account_id, index);
+            if task.voting {
+                self.internal_lock(account_id, &task_args.token_id, task.amount / task_args.account_list.len() as u128);
+            }
+        }
+        self.internal_add_creator_task(creator_id, index);
+        self.internal_credit_treasury(creator_id, &task_args.token_id, amount - task.amount);
+        token_amount -= amount;
+        token.live_tasks += 1;
+        token.tasks.insert(&index);
+        token.amount += task.amount;
+        token.amount_left += task.amount_left;
+        self.tokens.insert(&task_args.token_id, &token);
+        self.tasks.push(&TaskEntry::Task(task));
+        Ok(token_amount.into())
+    }
+
+    /// Task `index` of `creator_id` to be funded with `amount` of `token_id` out of
+    /// `token_amount` received.
+    fn internal_get_funded_task(&self, index: u32, creator_id: &AccountId, token_id: &AccountId, amount: u128, token_amount: u128) -> Result<Task, TaskError> {
+        let mut task = match self.tasks.get(index as u64) {
+            Some(TaskEntry::Task(task)) => task,
+            Some(TaskEntry::Archived(_)) => return Err(TaskError::TaskArchived),
+            None => return Err(TaskError::TaskNotFound)
+        };
+        self.internal_resolve_start(&mut task);
+        if task.creator_id != *creator_id {
+            return Err(TaskError::NotCreator);
+        }
+        if task.merkle_root.is_some() {
+            return Err(TaskError::MerkleTaskLocked);
+        }
+        if task.staking.is_some() {
+            return Err(TaskError::StakingTaskLocked);
+        }
+        if !task.legs.is_empty() {
+            return Err(TaskError::LegsTaskLocked);
+        }
+        if task.token_id != *token_id {
+            return Err(TaskError::TokenNotMatch);
+        }
+        if amount == 0 {
+            return Err(TaskError::ZeroAmount);
+        }
+        if token_amount < amount {
+            return Err(TaskError::NotEnoughBalance);
+        }
+        Ok(task)
+    }
+
+    pub(crate) fn internal_reactivate_task(&mut self, task: &mut Task) {
+        if task.status == TaskStatus::Completed {
+            task.status = TaskStatus::Active;
+            let mut token = self.tokens.get(&task.token_id).unwrap();
+            token.live_tasks += 1;
+            self.tokens.insert(&task.token_id, &token);
+        }
+    }
+
+    /// Adds new beneficiaries to task `index`, funded by `amount` split equally between
+    /// them. They vest from the current vesting period on.
+    pub(crate) fn internal_add_beneficiaries(&mut self, index: u32, creator_id: &AccountId, token_id: &AccountId, account_list: &[AccountId], amount: u128, token_amount: u128) -> Result<U128, TaskError> {
+        let mut task = self.internal_get_funded_task(index, creator_id, token_id, amount, token_amount)?;
+        let claim_count = (task.end_time - task.start_time) / task.vesting_period;
+        validate_allocation(account_list, amount, claim_count, false)?;
+        if let Some(account_id) = account_list.iter().find(|account_id| task.accounts.get(account_id).is_some()) {
+            return Err(TaskError::DuplicateAccount(account_id.clone()));
+        }
+        self.internal_reactivate_task(&mut task);
+        let base_time = align_time(&task, env::block_timestamp());
+        let allocated = task.allocate(account_list, amount, base_time);
+        task.amount += allocated;
+        self.internal_add_token_totals(token_id, allocated, allocated);
+        self.internal_credit_treasury(creator_id, token_id, amount - allocated);
+        for account_id in account_list {
+            self.internal_add_position(account_id, index);
+            if task.voting {
+                self.internal_lock(account_id, token_id, allocated / account_list.len() as u128);
+            }
+        }
+        self.internal_save_task(index, task);
+        Ok((token_amount - amount).into())
+    }
+
+    /// Adds `amount` to the allocation of `account_id` in task `index`. What is vested
+    /// so far is kept, and the rest vests from the current vesting period on.
+    pub(crate) fn internal_top_up(&mut self, index: u32, creator_id: &AccountId, token_id: &AccountId, account_id: &AccountId, amount: u128, token_amount: u128) -> Result<U128, TaskError> {
+        let mut task = self.internal_get_funded_task(index, creator_id, token_id, amount, token_amount)?;
+        let now = env::block_timestamp();
+        let base_time = align_time(&task, now);
+        let mut claim_info = match task.accounts.get(account_id) {
+            Some(claim_info) => ClaimInfo {
+                base_amount: get_unlocked_amount(&task, &claim_info, now),
+                base_time: base_time,
+                ..claim_info
+            },
+            None => ClaimInfo {
+                amount: 0,
+                amount_left: 0,
+                base_amount: 0,
+                base_time: base_time,
+                claim_time: base_time,
+                transfer_failed: false,
+                accepted: task.acceptance_deadline.is_none(),
+                rewards: 0,
+                reward_debt: 0,
+                legs_claimed: vec![],
+                claims_in_flight: 0,
+                storage_paid: 0
+            }
+        };
+        claim_info.amount += amount;
+        claim_info.amount_left += amount;
+        task.insert_account(account_id, &claim_info);
+        self.internal_add_position(account_id, index);
+        if task.voting {
+            self.internal_lock(account_id, token_id, amount);
+        }
+        self.internal_reactivate_task(&mut task);
+        task.amount += amount;
+        task.amount_left += amount;
+        self.internal_add_token_totals(token_id, amount, amount);
+        self.internal_save_task(index, task);
+        Ok((token_amount - amount).into())
+    }
+
+    /// Adds `amount` of `token_id` to task `index` as a new leg.
+    pub(crate) fn internal_add_leg(&mut self, index: u32, creator_id: &AccountId, token_id: &AccountId, amount: u128, token_amount: u128) -> Result<U128, TaskError> {
+        let mut task = match self.tasks.get(index as u64) {
+            Some(TaskEntry::Task(task)) => task,
+            Some(TaskEntry::Archived(_)) => return Err(TaskError::TaskArchived),
+            None => return Err(TaskError::TaskNotFound)
+        };
+        self.internal_resolve_start(&mut task);
+        if task.creator_id != *creator_id {
+            return Err(TaskError::NotCreator);
+        }
+        if self.tokens.get(token_id).is_none() {
+            return Err(TaskError::TokenNotRegistered);
+        }
+        if task.token_id == *token_id || task.legs.iter().any(|leg| leg.token_id == *token_id) || task.status == TaskStatus::Completed {
+            return Err(TaskError::InvalidLeg);
+        }
+        if amount == 0 {
+            return Err(TaskError::ZeroAmount);
+        }
+        if token_amount < amount {
+            return Err(TaskError::NotEnoughBalance);
+        }
+        task.legs.push(Leg {
+            token_id: token_id.clone(),
+            amount,
+            amount_left: amount,
         });
-        token_amount.into()
+        let mut token = self.tokens.get(token_id).unwrap();
+        token.tasks.insert(&index);
+        // live until the leg is claimed, swept or archived
+        token.live_tasks += 1;
+        token.amount += amount;
+        token.amount_left += amount;
+        self.tokens.insert(token_id, &token);
+        self.internal_save_task(index, task);
+        Ok((token_amount - amount).into())
+    }
+
+    /// Takes what is left of `leg` back into the treasury of `creator_id`.
+    pub(crate) fn internal_take_back_leg(&mut self, creator_id: &AccountId, leg: &Leg) {
+        if let Some(mut token) = self.tokens.get(&leg.token_id) {
+            token.amount -= leg.amount_left;
+            token.amount_left -= leg.amount_left;
+            if leg.amount_left > 0 {
+                token.live_tasks -= 1;
+            }
+            self.tokens.insert(&leg.token_id, &token);
+        }
+        self.internal_credit_treasury(creator_id, &leg.token_id, leg.amount_left);
+    }
+
+    pub(crate) fn internal_transfer_leg_claim(&self, token_id: &AccountId, index: u32, claimer_id: &AccountId, leg_index: u32, amount: u128) -> Promise {
+        ext_fungible_token::ft_transfer(claimer_id.clone(), amount.into(), None, token_id, 1, GAS_FOR_FT_TRANSFER).then(
+            ext_self::on_claim_leg(index, claimer_id.clone(), leg_index, amount.into(), &env::current_account_id(), 0, GAS_FOR_ON_CLAIM_LEG)
+        )
+    }
+
+    /// Takes the claim of `amount` and `leg_amounts` off `claim_info` and `task` before
+    /// anything is transferred, so that it can't be claimed twice. The callbacks give
+    /// back what fails to transfer.
+    pub(crate) fn internal_debit_claim(&mut self, task: &mut Task, claim_info: &mut ClaimInfo, amount: u128, leg_amounts: &[u128]) {
+        for (leg_index, leg_amount) in leg_amounts.iter().copied().enumerate().filter(|(_, leg_amount)| *leg_amount > 0) {
+            let leg = &mut task.legs[leg_index];
+            leg.amount_left -= leg_amount;
+            claim_info.legs_claimed[leg_index] += leg_amount;
+            claim_info.claims_in_flight += 1;
+            if let Some(mut token) = self.tokens.get(&leg.token_id) {
+                token.amount_left -= leg_amount;
+                if leg.amount_left == 0 {
+                    token.live_tasks -= 1;
+                }
+                self.tokens.insert(&leg.token_id, &token);
+            }
+        }
+        if amount == 0 {
+            return;
+        }
+        // rewards earned so far are kept, what is claimed earns nothing more
+        if let Some(staking) = task.staking.as_ref() {
+            claim_info.rewards = staking.pending_rewards(claim_info);
+            claim_info.reward_debt = staking.accrued(claim_info.amount_left - amount);
+        }
+        claim_info.amount_left -= amount;
+        claim_info.claim_time = env::block_timestamp();
+        claim_info.claims_in_flight += 1;
+        task.amount_left -= amount;
+        let mut token = self.tokens.get(&task.token_id).unwrap();
+        token.amount_left -= amount;
+        if task.amount_left == 0 && task.status == TaskStatus::Active {
+            task.status = TaskStatus::Completed;
+            token.live_tasks -= 1;
+        }
+        self.tokens.insert(&task.token_id, &token);
+    }
+
+    /// Gives back the claim of `amount`, along with `reward` of a staking task, once
+    /// its transfer failed.
+    pub(crate) fn internal_restore_claim(&mut self, task: &mut Task, claim_info: &mut ClaimInfo, amount: u128, reward: u128) {
+        if let Some(staking) = task.staking.as_ref() {
+            claim_info.rewards = staking.pending_rewards(claim_info) + reward;
+            claim_info.reward_debt = staking.accrued(claim_info.amount_left + amount);
+        }
+        claim_info.amount_left += amount;
+        claim_info.transfer_failed = true;
+        task.amount_left += amount;
+        let mut token = self.tokens.get(&task.token_id).unwrap();
+        token.amount_left += amount;
+        if task.amount_left == amount && task.status == TaskStatus::Completed {
+            task.status = TaskStatus::Active;
+            token.live_tasks += 1;
+        }
+        self.tokens.insert(&task.token_id, &token);
     }
 
-    pub(crate) fn internal_add_token(&mut self, token_id: AccountId) {
-        assert!(self.tokens.iter().find(|token| *token == token_id).is_none(), "token already exist");
-        ext_fungible_token::storage_deposit(Some(env::current_account_id().try_into().unwrap()), None, &token_id, env::attached_deposit(), env::prepaid_gas() / 3).then(
-            ext_self::on_add_token(token_id.clone(), &env::current_account_id(), 0, env::prepaid_gas() / 3)
+    /// Saves the entry of `account_id` in task `index`, or removes it once it is paid out
+    /// of every token and none of its transfers is in flight. A merkle allocation is kept
+    /// once paid out, so that its proof can't be used again. Returns the bytes freed
+    /// that the beneficiary paid for, refunded to it.
+    pub(crate) fn internal_settle_account(&mut self, task: &mut Task, index: u32, account_id: &AccountId, claim_info: &ClaimInfo) -> StorageUsage {
+        if claim_info.claims_in_flight == 0 && claim_info.amount_left == 0 && task.merkle_root.is_none() && legs_settled(task, claim_info) {
+            task.remove_account(account_id);
+            self.internal_remove_position(account_id, index);
+            self.internal_refund_storage_deposit(account_id, claim_info.storage_paid as u128 * env::storage_byte_cost());
+            claim_info.storage_paid
+        } else {
+            task.accounts.insert(account_id, claim_info);
+            0
+        }
+    }
+
+    /// Transfers `amount` of `token_id` swept from an expired task to `creator_id`,
+    /// into the creator's treasury if the transfer fails.
+    pub(crate) fn internal_transfer_swept(&self, creator_id: &AccountId, token_id: &AccountId, amount: u128) {
+        if amount > 0 {
+            ext_fungible_token::ft_transfer(creator_id.clone(), amount.into(), None, token_id, 1, GAS_FOR_FT_TRANSFER).then(
+                ext_self::on_withdraw(creator_id.clone(), token_id.clone(), amount.into(), &env::current_account_id(), 0, GAS_FOR_ON_WITHDRAW)
+            );
+        }
+    }
+
+    pub(crate) fn internal_add_token_totals(&mut self, token_id: &AccountId, amount: u128, amount_left: u128) {
+        let mut token = self.tokens.get(token_id).unwrap();
+        token.amount += amount;
+        token.amount_left += amount_left;
+        self.tokens.insert(token_id, &token);
+    }
+
+    pub(crate) fn internal_add_token(&mut self, token_id: AccountId, payer_id: AccountId) {
+        assert!(self.tokens.get(&token_id).is_none(), "token already exist");
+        self.internal_assert_storage_available(&payer_id, TOKEN_STORAGE_USAGE);
+        ext_fungible_token::storage_balance_of(env::current_account_id().try_into().unwrap(), &token_id, 0, GAS_FOR_FT_VIEW).and(
+            ext_fungible_token::storage_balance_bounds(&token_id, 0, GAS_FOR_FT_VIEW)
+        ).then(
+            ext_self::on_storage_checked(token_id.clone(), payer_id, env::attached_deposit().into(), &env::current_account_id(), 0, GAS_FOR_ON_STORAGE_CHECKED)
         );
     }
 
+    pub(crate) fn internal_add_creator_task(&mut self, creator_id: &AccountId, index: u32) {
+        let mut tasks = self.creator_tasks.get(creator_id).unwrap_or_else(|| {
+            UnorderedSet::new(StorageKey::CreatorTaskIndexes { account_hash: env::sha256(creator_id.as_bytes()).try_into().unwrap() })
+        });
+        tasks.insert(&index);
+        self.creator_tasks.insert(creator_id, &tasks);
+    }
+
+    /// Takes archived task `index` out of the tasks of its creator and of its tokens.
+    pub(crate) fn internal_remove_task_index(&mut self, index: u32, task: &Task) {
+        if let Some(mut tasks) = self.creator_tasks.get(&task.creator_id) {
+            tasks.remove(&index);
+            self.creator_tasks.insert(&task.creator_id, &tasks);
+        }
+        for token_id in std::iter::once(&task.token_id).chain(task.legs.iter().map(|leg| &leg.token_id)) {
+            if let Some(mut token) = self.tokens.get(token_id) {
+                token.tasks.remove(&index);
+                self.tokens.insert(token_id, &token);
+            }
+        }
+    }
+
+    /// Takes `amount` out of `task`, back into its creator's treasury.
+    pub(crate) fn internal_take_back(&mut self, task: &mut Task, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+        task.amount -= amount;
+        task.amount_left -= amount;
+        let mut token = self.tokens.get(&task.token_id).unwrap();
+        token.amount -= amount;
+        token.amount_left -= amount;
+        if task.amount_left == 0 && task.status == TaskStatus::Active {
+            task.status = TaskStatus::Completed;
+            token.live_tasks -= 1;
+        }
+        self.tokens.insert(&task.token_id, &token);
+        self.internal_credit_treasury(&task.creator_id, &task.token_id, amount);
+    }
+
+    pub(crate) fn internal_credit_treasury(&mut self, creator_id: &AccountId, token_id: &AccountId, amount: u128) {
+        if amount > 0 {
+            let key = (creator_id.clone(), token_id.clone());
+            self.treasuries.insert(&key, &(self.treasuries.get(&key).unwrap_or(0) + amount));
+        }
+    }
+
+    /// Registers `account_list` with the token, paid from the storage deposit of
+    /// `payer_id`. Accounts already registered are not charged.
+    pub(crate) fn internal_register_beneficiaries(&mut self, token_id: &AccountId, account_list: Vec<AccountId>, payer_id: &AccountId) {
+        let token = self.tokens.get(token_id).unwrap();
+        self.internal_spend_storage_deposit(payer_id, token.storage_balance_min * account_list.len() as u128);
+        for account_id in account_list {
+            ext_fungible_token::storage_balance_of(account_id.clone().try_into().unwrap(), token_id, 0, GAS_FOR_FT_VIEW).then(
+                ext_self::on_beneficiary_storage_checked(token_id.clone(), account_id, payer_id.clone(), token.storage_balance_min.into(), &env::current_account_id(), 0, GAS_FOR_ON_BENEFICIARY_STORAGE_CHECKED)
+            );
+        }
+    }
+
+    /// Transfers the claim of `amount`, first withdrawing it from the pool of a staking task.
+    /// `storage_cost` is what the claimer paid for the storage of the claim.
+    pub(crate) fn internal_transfer_claim(&mut self, token_id: &AccountId, index: u32, claimer_id: AccountId, amount: U128, storage_cost: u128) -> Promise {
+        if self.internal_get_task(index).staking.is_some() {
+            return self.internal_unstake_and_claim(index, claimer_id, amount, storage_cost);
+        }
+        self.internal_send_claim(token_id, index, claimer_id, amount, 0, storage_cost)
+    }
+
+    /// Transfers `amount` of task `index` to the claimer, along with `reward` of a staking task.
+    pub(crate) fn internal_send_claim(&self, token_id: &AccountId, index: u32, claimer_id: AccountId, amount: U128, reward: u128, storage_cost: u128) -> Promise {
+        ext_fungible_token::ft_transfer(claimer_id.clone(), (u128::from(amount) + reward).into(), None, token_id, 1, GAS_FOR_FT_TRANSFER).then(
+            ext_self::on_claim(index, claimer_id, amount, reward.into(), storage_cost.into(), &env::current_account_id(), 0, GAS_FOR_ON_CLAIM)
+        )
+    }
+
+    pub(crate) fn internal_get_task(&self, index: u32) -> Task {
+        match self.tasks.get(index as u64).expect("task not exist") {
+            TaskEntry::Task(mut task) => {
+                self.internal_resolve_start(&mut task);
+                task
+            },
+            TaskEntry::Archived(_) => env::panic(b"task archived"),
+        }
+    }
+
+    /// Starts `task` at the TGE of its token if it is waiting for it and the TGE is set.
+    pub(crate) fn internal_resolve_start(&self, task: &mut Task) {
+        if task.awaiting_tge {
+            if let Some(tge) = self.tokens.get(&task.token_id).and_then(|token| token.tge) {
+                task.start(tge);
+            }
+        }
+    }
+
+    /// Task `index`, which only its creator can manage.
+    pub(crate) fn internal_get_creator_task(&self, index: u32, creator_id: &AccountId) -> Task {
+        let task = self.internal_get_task(index);
+        assert!(task.creator_id == *creator_id, "task creator only");
+        task
+    }
+
+    pub(crate) fn internal_save_task(&mut self, index: u32, task: Task) {
+        self.tasks.replace(index as u64, &TaskEntry::Task(task));
+    }
+
+    /// Records that `account_id` has a position in task `index`.
+    pub(crate) fn internal_add_position(&mut self, account_id: &AccountId, index: u32) {
+        let mut positions = self.positions.get(account_id).unwrap_or_else(|| {
+            UnorderedSet::new(StorageKey::AccountPositions { account_hash: env::sha256(account_id.as_bytes()).try_into().unwrap() })
+        });
+        if positions.insert(&index) {
+            self.positions.insert(account_id, &positions);
+        }
+    }
+
+    pub(crate) fn internal_remove_position(&mut self, account_id: &AccountId, index: u32) {
+        if let Some(mut positions) = self.positions.get(account_id) {
+            positions.remove(&index);
+            if positions.is_empty() {
+                self.positions.remove(account_id);
+            } else {
+                self.positions.insert(account_id, &positions);
+            }
+        }
+    }
+
+    pub(crate) fn internal_refund(&self, account_id: &AccountId, amount: u128) {
+        if amount > 0 {
+            Promise::new(account_id.clone()).transfer(amount);
+        }
+    }
+
 }
 
 #[near_bindgen]
 impl Lockup {
+    /// Settles the claim of `amount`, taken off the allocation by `claim`: gives it back
+    /// if the transfer failed, and refunds the claimer what its checkpoint didn't use of
+    /// `storage_cost`.
+    #[private]
+    pub fn on_claim(&mut self, index: u32, claimer_id: AccountId, amount: U128, reward: U128, storage_cost: U128) {
+        let storage_cost: u128 = storage_cost.into();
+        let succeeded = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => true,
+            PromiseResult::Failed => false
+        };
+        let initial_storage = env::storage_usage();
+        let mut task = self.internal_get_task(index);
+        let mut claim_info = task.accounts.get(&claimer_id).unwrap();
+        claim_info.claims_in_flight -= 1;
+        if succeeded {
+            claim_info.transfer_failed = false;
+        } else {
+            log!("failed to claim, {} may not be registered with the token", claimer_id);
+            self.internal_restore_claim(&mut task, &mut claim_info, amount.into(), reward.into());
+            // the claim withdrawn from the pool stays with this contract until claimed again
+            if let Some(staking) = task.staking.as_mut() {
+                staking.unstaked += u128::from(amount);
+            }
+        }
+        let released = self.internal_settle_account(&mut task, index, &claimer_id, &claim_info);
+        let creator_id = task.creator_id.clone();
+        let token_id = task.token_id.clone();
+        let voting = task.voting;
+        self.internal_save_task(index, task);
+        self.internal_update_storage(&creator_id, initial_storage - released);
+        if !succeeded || !voting {
+            self.internal_refund_storage_deposit(&claimer_id, storage_cost);
+            return;
+        }
+        // paid in advance by the claimer, the claim can't fail on anyone's deposit
+        let initial_storage = env::storage_usage();
+        self.internal_unlock(&claimer_id, &token_id, amount.into());
+        let used = env::storage_usage().saturating_sub(initial_storage) as u128 * env::storage_byte_cost();
+        self.internal_refund_storage_deposit(&claimer_id, storage_cost.saturating_sub(used));
+    }
+
+    /// Settles the claim of `amount` of leg `leg_index`, independently of the other
+    /// tokens of the task, giving it back if the transfer failed.
     #[private]
-    pub fn on_claim(&mut self, index: u32, claimer_id: AccountId, amount: U128) {
+    pub fn on_claim_leg(&mut self, index: u32, claimer_id: AccountId, leg_index: u32, amount: U128) {
+        let initial_storage = env::storage_usage();
+        let mut task = self.internal_get_task(index);
+        let leg_index = leg_index as usize;
+        let amount: u128 = amount.into();
+        let mut claim_info = task.accounts.get(&claimer_id).unwrap();
+        claim_info.claims_in_flight -= 1;
         match env::promise_result(0) {
             PromiseResult::NotReady => unreachable!(),
-            PromiseResult::Successful(_) => {
-                let mut task = self.tasks.get(index as u64).unwrap();
-                let mut claim_info = task.accounts.get(&claimer_id).unwrap();
-                claim_info.amount_left -= u128::from(amount);
-                claim_info.claim_time = env::block_timestamp();
-                task.accounts.insert(&claimer_id, &claim_info);
-            },
+            PromiseResult::Successful(_) => {},
             PromiseResult::Failed => {
-                log!("failed to claim");
+                let leg = &mut task.legs[leg_index];
+                log!("failed to claim {} of task {}, {} may not be registered with it", leg.token_id, index, claimer_id);
+                claim_info.legs_claimed[leg_index] -= amount;
+                leg.amount_left += amount;
+                if let Some(mut token) = self.tokens.get(&leg.token_id) {
+                    token.amount_left += amount;
+                    if leg.amount_left == amount {
+                        token.live_tasks += 1;
+                    }
+                    self.tokens.insert(&leg.token_id, &token);
+                }
             }
         }
+        let released = self.internal_settle_account(&mut task, index, &claimer_id, &claim_info);
+        let creator_id = task.creator_id.clone();
+        self.internal_save_task(index, task);
+        self.internal_update_storage(&creator_id, initial_storage - released);
     }
 
+    /// Registers this contract with the token only if it is not registered yet,
+    /// and returns whatever is not needed for the storage deposit to `payer_id`, all of
+    /// it if the token's answers can't be read.
     #[private]
-    pub fn on_add_token(&mut self, token_id: AccountId) {
+    pub fn on_storage_checked(&mut self, token_id: AccountId, payer_id: AccountId, deposit: U128) {
+        let deposit: u128 = deposit.into();
+        let parsed = match (env::promise_result(0), env::promise_result(1)) {
+            (PromiseResult::Successful(balance), PromiseResult::Successful(bounds)) => serde_json::from_slice::<Option<StorageBalance>>(&balance).ok()
+                .zip(serde_json::from_slice::<StorageBalanceBounds>(&bounds).ok()),
+            _ => None
+        };
+        let (balance, bounds) = match parsed {
+            Some(parsed) => parsed,
+            None => {
+                log!("failed to query storage of {}", token_id);
+                self.internal_refund(&payer_id, deposit);
+                return;
+            }
+        };
+        let storage_cost = match balance {
+            Some(_) => {
+                log!("already registered with {}", token_id);
+                0
+            },
+            None => u128::from(bounds.min)
+        };
+        if deposit < storage_cost {
+            log!("not enough deposit for storage of {}, {} required", token_id, storage_cost);
+            self.internal_refund(&payer_id, deposit);
+            return;
+        }
+        self.internal_refund(&payer_id, deposit - storage_cost);
+        ext_fungible_token::storage_deposit(Some(env::current_account_id().try_into().unwrap()), Some(true), &token_id, storage_cost, GAS_FOR_STORAGE_DEPOSIT).and(
+            ext_fungible_token::ft_metadata(&token_id, 0, GAS_FOR_FT_VIEW)
+        ).then(
+            ext_self::on_add_token(token_id.clone(), payer_id, storage_cost.into(), bounds.min, &env::current_account_id(), 0, GAS_FOR_ON_ADD_TOKEN)
+        );
+    }
+
+    #[private]
+    pub fn on_add_token(&mut self, token_id: AccountId, payer_id: AccountId, storage_cost: U128, storage_balance_min: U128) {
         match env::promise_result(0) {
             PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) if self.tokens.get(&token_id).is_some() => {
+                // an overlapping `add_token` got here first, its registration stands
+                log!("token {} already exist", token_id);
+                self.internal_refund(&payer_id, storage_cost.into());
+            },
             PromiseResult::Successful(_) => {
-                self.tokens.push(&token_id);
+                let initial_storage = env::storage_usage();
+                let metadata = match env::promise_result(1) {
+                    PromiseResult::Successful(value) => {
+                        serde_json::from_slice::<FungibleTokenMetadata>(&value).ok().map(|metadata| TokenMetadata {
+                            symbol: metadata.symbol,
+                            decimals: metadata.decimals,
+                            icon: metadata.icon.filter(|icon| icon.len() <= MAX_ICON_LENGTH),
+                        })
+                    },
+                    _ => None
+                };
+                self.tokens.insert(&token_id, &Token::new(&token_id, metadata, storage_balance_min.into()));
+                self.internal_update_storage(&payer_id, initial_storage);
             },
             PromiseResult::Failed => {
-                log!("failed to claim");
+                // the failed storage deposit has been refunded to this contract
+                log!("failed to register with {}", token_id);
+                self.internal_refund(&payer_id, storage_cost.into());
             }
         }
     }
+
+    /// Puts a failed withdrawal back into the treasury of `creator_id`.
+    #[private]
+    pub fn on_withdraw(&mut self, creator_id: AccountId, token_id: AccountId, amount: U128) {
+        if let PromiseResult::Failed = env::promise_result(0) {
+            log!("failed to withdraw, {} may not be registered with the token", creator_id);
+            self.internal_credit_treasury(&creator_id, &token_id, amount.into());
+        }
+    }
+
+    /// Registers `account_id` with the token if it isn't yet. Otherwise, or if the token's
+    /// answer can't be read, `storage_cost` goes back to the storage deposit of `payer_id`.
+    #[private]
+    pub fn on_beneficiary_storage_checked(&mut self, token_id: AccountId, account_id: AccountId, payer_id: AccountId, storage_cost: U128) {
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(value) if matches!(serde_json::from_slice::<Option<StorageBalance>>(&value).ok(), Some(None)) => {
+                ext_fungible_token::storage_deposit(Some(account_id.try_into().unwrap()), Some(true), &token_id, storage_cost.into(), GAS_FOR_STORAGE_DEPOSIT);
+            },
+            _ => {
+                self.internal_refund_storage_deposit(&payer_id, storage_cost.into());
+            }
+        }
+    }
+
+    /// Registers the claimer with the token from the attached deposit if needed,
+    /// then continues with the claim.
+    #[private]
+    pub fn on_claimer_storage_checked(&mut self, index: u32, claimer_id: AccountId, amount: U128, deposit: U128, storage_cost: U128) {
+        let task = self.internal_get_task(index);
+        let token = self.tokens.get(&task.token_id).unwrap();
+        let deposit: u128 = deposit.into();
+        let registered = match env::promise_result(0) {
+            // an answer that can't be read is taken as registered, the deposit is refunded
+            PromiseResult::Successful(value) => !matches!(serde_json::from_slice::<Option<StorageBalance>>(&value).ok(), Some(None)),
+            _ => false
+        };
+        if registered || deposit < token.storage_balance_min {
+            if !registered {
+                log!("not enough deposit to register with {}, {} required", task.token_id, token.storage_balance_min);
+            }
+            Promise::new(claimer_id.clone()).transfer(deposit);
+            self.internal_transfer_claim(&task.token_id, index, claimer_id, amount, storage_cost.into());
+            return;
+        }
+        if deposit > token.storage_balance_min {
+            Promise::new(claimer_id.clone()).transfer(deposit - token.storage_balance_min);
+        }
+        ext_fungible_token::storage_deposit(Some(claimer_id.clone().try_into().unwrap()), Some(true), &task.token_id, token.storage_balance_min, GAS_FOR_STORAGE_DEPOSIT).then(
+            self.internal_transfer_claim(&task.token_id, index, claimer_id, amount, storage_cost.into())
+        );
+    }
+}
+
+/// Logs why a task was rejected and returns the whole amount to the sender.
+fn reject_task(err: TaskError, amount: U128) -> PromiseOrValue<U128> {
+    log!("{}", err);
+    PromiseOrValue::Value(amount)
 }
 
 #[near_bindgen]
 #[allow(unreachable_code)]
 impl FungibleTokenReceiver for Lockup {
     /// Callback on receiving tokens by this contract.
-    /// `msg` format is either "" for deposit or `TokenReceiverMessage`.
+    /// `msg` is the JSON of `TokenReceiverMessage`, invalid tasks are rejected and fully refunded.
     fn ft_on_transfer(
         &mut self,
         sender_id: ValidAccountId,
         amount: U128,
         msg: String,
     ) -> PromiseOrValue<U128> {
-        assert!(!msg.is_empty(), "msg should not be empty");
         let token_in = env::predecessor_account_id();
-        let task_args: TaskArgs = serde_json::from_str(&msg).unwrap();
-        assert!(task_args.token_id == token_in, "token not match");
-        assert!(self.owner_id == sender_id.to_string(), "contract owner only");
-        let amount_left = self.internal_add_task(task_args, amount.into());
+        let message: TokenReceiverMessage = match serde_json::from_str(&msg) {
+            Ok(message) => message,
+            Err(_) => return reject_task(TaskError::InvalidMessage, amount)
+        };
+        if let TokenReceiverMessage::Unstaked { stake_key } = &message {
+            return match self.internal_receive_unstaked(sender_id.as_ref(), &token_in, stake_key, amount.into()) {
+                Ok(()) => PromiseOrValue::Value(0.into()),
+                Err(err) => reject_task(err, amount)
+            };
+        }
+        let creator_id: AccountId = sender_id.into();
+        if self.storage_accounts.get(&creator_id).is_none() {
+            return reject_task(TaskError::CreatorNotRegistered, amount);
+        }
+        let initial_storage = env::storage_usage();
+        let (result, account_list, register_accounts, stake) = match message {
+            TokenReceiverMessage::AddTask(task_args) => {
+                if task_args.token_id != token_in {
+                    return reject_task(TaskError::TokenNotMatch, amount);
+                }
+                let register_accounts = task_args.register_accounts.unwrap_or(false);
+                let account_list = task_args.account_list.clone();
+                let stake = task_args.staking_pool.is_some();
+                (self.internal_add_task(task_args, &creator_id, amount.into()), account_list, register_accounts, stake)
+            },
+            TokenReceiverMessage::AddBeneficiaries { index, account_list, amount: allocation, register_accounts } => {
+                let register_accounts = register_accounts.unwrap_or(false);
+                if register_accounts && account_list.len() > MAX_REGISTERED_ACCOUNTS {
+                    return reject_task(TaskError::TooManyRegistrations, amount);
+                }
+                let result = self.internal_add_beneficiaries(index, &creator_id, &token_in, &account_list, allocation.into(), amount.into());
+                (result, account_list, register_accounts, false)
+            },
+            TokenReceiverMessage::TopUp { index, account_id, amount: allocation } => {
+                (self.internal_top_up(index, &creator_id, &token_in, &account_id, allocation.into(), amount.into()), vec![], false, false)
+            },
+            TokenReceiverMessage::AddLeg { index, amount: leg_amount } => {
+                (self.internal_add_leg(index, &creator_id, &token_in, leg_amount.into(), amount.into()), vec![], false, false)
+            },
+            TokenReceiverMessage::Unstaked { .. } => unreachable!()
+        };
+        let amount_left = match result {
+            Ok(amount_left) => amount_left,
+            Err(err) => return reject_task(err, amount)
+        };
+        self.internal_update_storage(&creator_id, initial_storage);
+        if register_accounts {
+            self.internal_register_beneficiaries(&token_in, account_list, &creator_id);
+        }
+        if stake {
+            self.internal_stake(self.tasks.len() as u32 - 1);
+        }
         PromiseOrValue::Value(amount_left)
     }
 }
\ No newline at end of file
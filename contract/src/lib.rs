@@ -13,20 +13,79 @@
 
 // To conserve gas, efficient serialization is achieved through Borsh (http://borsh.io/)
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::{AccountId, PanicOnDefault, env, ext_contract, log, near_bindgen, setup_alloc};
-use near_sdk::collections::{LookupMap, UnorderedMap, Vector};
+use near_sdk::{AccountId, BorshStorageKey, CryptoHash, Gas, PanicOnDefault, Promise, assert_one_yocto, env, ext_contract, log, near_bindgen, setup_alloc};
+use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet, Vector};
 use near_sdk::serde::{Serialize, Deserialize};
 use near_sdk::json_types::{Base58CryptoHash, U128, U64, ValidAccountId};
 use near_contract_standards::fungible_token::metadata::{FungibleTokenMetadata};
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
-use utils::get_claim_amount;
+use near_contract_standards::storage_management::{StorageBalance, StorageBalanceBounds};
+use errors::TaskError;
+use internal::{Beneficiary, TaskArgs};
+use merkle::{leaf_hash, verify_proof};
+use storage::{StorageAccount, CLAIM_STORAGE_USAGE, LEG_CLAIM_STORAGE_USAGE};
+use utils::{align_time, get_claim_amount, get_claim_amount_at, get_leg_claim_amounts_at, get_next_unlock, get_unlocked_amount, legs_settled};
 use std::convert::TryInto;
 
 setup_alloc!();
 
+const GAS_FOR_FT_VIEW: Gas = 10_000_000_000_000;
+const GAS_FOR_STORAGE_DEPOSIT: Gas = 20_000_000_000_000;
+const GAS_FOR_ON_ADD_TOKEN: Gas = 20_000_000_000_000;
+const GAS_FOR_ON_STORAGE_CHECKED: Gas = GAS_FOR_STORAGE_DEPOSIT + GAS_FOR_FT_VIEW + GAS_FOR_ON_ADD_TOKEN + 20_000_000_000_000;
+const GAS_FOR_ON_BENEFICIARY_STORAGE_CHECKED: Gas = GAS_FOR_STORAGE_DEPOSIT + 10_000_000_000_000;
+/// Longest token icon `add_token` keeps from the metadata, longer ones are dropped.
+const MAX_ICON_LENGTH: usize = 1024;
+/// Accounts `register_accounts` registers at most, each taking `GAS_FOR_FT_VIEW` and
+/// `GAS_FOR_ON_BENEFICIARY_STORAGE_CHECKED` out of the transfer of the task.
+pub const MAX_REGISTERED_ACCOUNTS: usize = 4;
+/// Accounts `register_beneficiaries` registers at most in a call, which has the gas for
+/// about that many registrations.
+pub const MAX_BATCH_REGISTRATIONS: usize = 6;
+const GAS_FOR_FT_TRANSFER: Gas = 20_000_000_000_000;
+const GAS_FOR_ON_CLAIM: Gas = 20_000_000_000_000;
+const GAS_FOR_ON_CLAIM_LEG: Gas = 20_000_000_000_000;
+const GAS_FOR_ON_WITHDRAW: Gas = 20_000_000_000_000;
+const GAS_FOR_MIGRATE: Gas = 50_000_000_000_000;
+/// Start time of tasks waiting for the TGE of their token, far enough in the future that
+/// nothing vests until they are started.
+const PENDING_START_TIME: u64 = u64::MAX / 2;
+/// Time after the claim deadline for claims still in flight to settle before a sweep.
+const SWEEP_DELAY: u64 = 10 * 60 * 1_000_000_000;
+const GAS_FOR_STAKE: Gas = 50_000_000_000_000;
+const GAS_FOR_ON_STAKED: Gas = 10_000_000_000_000;
+const GAS_FOR_POOL_WITHDRAW: Gas = 80_000_000_000_000;
+const GAS_FOR_ON_UNSTAKED: Gas = GAS_FOR_FT_TRANSFER + GAS_FOR_ON_CLAIM + 20_000_000_000_000;
+const GAS_FOR_ON_CLAIMER_STORAGE_CHECKED: Gas = GAS_FOR_STORAGE_DEPOSIT + GAS_FOR_POOL_WITHDRAW + GAS_FOR_ON_UNSTAKED + 20_000_000_000_000;
+
+pub mod errors;
 pub mod internal;
+pub mod merkle;
+pub mod migrate;
+pub mod staking;
+pub mod storage;
 pub mod utils;
 pub mod view;
+pub mod voting;
+
+#[derive(BorshStorageKey, BorshSerialize)]
+pub(crate) enum StorageKey {
+    Tokens,
+    Tasks,
+    StorageAccounts,
+    TaskAccounts { index: u64 },
+    LegacyClaimAmounts,
+    Positions,
+    AccountPositions { account_hash: CryptoHash },
+    TokenTasks { token_hash: CryptoHash },
+    CreatorTasks,
+    CreatorTaskIndexes { account_hash: CryptoHash },
+    Treasuries,
+    LockedBalances,
+    Delegates,
+    VotingCheckpoints,
+    AccountCheckpoints { key_hash: CryptoHash },
+}
 
 // Structs in Rust are similar to other languages, and may include impl keyword as shown below
 // Note: the names of the structs are not important when calling the smart contract, but the function names are
@@ -34,27 +93,164 @@ pub mod view;
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Lockup {
     owner_id: AccountId,
-    tokens: Vector<AccountId>,
-    tasks: Vector<Task>,
+    tokens: UnorderedMap<AccountId, Token>,
+    tasks: Vector<TaskEntry>,
+    storage_accounts: LookupMap<AccountId, StorageAccount>,
+    /// Indexes of the tasks each beneficiary has a position in.
+    positions: LookupMap<AccountId, UnorderedSet<u32>>,
+    /// Indexes of the tasks of each creator, archived ones are removed.
+    creator_tasks: LookupMap<AccountId, UnorderedSet<u32>>,
+    /// Tokens held for a creator out of any task, by `(creator_id, token_id)`.
+    treasuries: LookupMap<(AccountId, AccountId), u128>,
+    /// What each beneficiary has left to claim, by `(account_id, token_id)`.
+    locked_balances: LookupMap<(AccountId, AccountId), u128>,
+    /// Account each beneficiary delegated its voting power to, by `(account_id, token_id)`.
+    delegates: LookupMap<(AccountId, AccountId), AccountId>,
+    /// Voting power history of each account, by `(account_id, token_id)`.
+    voting_checkpoints: LookupMap<(AccountId, AccountId), Vector<Checkpoint>>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Token {
+    metadata: Option<TokenMetadata>,
+    storage_balance_min: u128,
+    live_tasks: u64,
+    /// Indexes of the tasks of this token, archived ones are removed.
+    tasks: UnorderedSet<u32>,
+    /// Sum of `amount` and `amount_left` of its tasks.
+    amount: u128,
+    amount_left: u128,
+    /// Token generation event, when the tasks waiting for it start.
+    tge: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(Debug, Clone)]
+pub struct TokenMetadata {
+    symbol: String,
+    decimals: u8,
+    icon: Option<String>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum TaskEntry {
+    Task(Task),
+    Archived(TaskSummary),
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(Debug, Clone, PartialEq)]
+pub enum TaskStatus {
+    Active,
+    Completed,
+    Draft,
 }
 
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct Task {
+    creator_id: AccountId,
     token_id: AccountId,
     accounts: LookupMap<AccountId, ClaimInfo>,
     start_time: u64,
     end_time: u64,
     vesting_period: u64,
     amount: u128,
-    single_claim_amount: u128,
+    amount_left: u128,
+    status: TaskStatus,
+    extensions: Vec<Extension>,
+    merkle_root: Option<CryptoHash>,
+    /// Set when beneficiaries have to accept their allocation before this time.
+    acceptance_deadline: Option<u64>,
+    /// Set when the unvested balance is deposited into a staking pool.
+    staking: Option<Staking>,
+    /// Other tokens vesting along with `token_id`.
+    legs: Vec<Leg>,
+    /// Set when what is not claimed by this time can be swept back by the creator.
+    claim_deadline: Option<u64>,
+    /// Set while the task waits for `start_task` or the TGE of its token. Its times
+    /// are then relative to `PENDING_START_TIME`.
+    awaiting_tge: bool,
+    /// Number of entries in `accounts`, which have to be cleared before archiving.
+    account_count: u64,
+    /// Set when allocations count for the voting power of their beneficiaries. Each claim
+    /// then writes a checkpoint, paid by the claimer.
+    voting: bool,
+}
+
+/// `amount` of another token shared by the beneficiaries of a task in proportion to
+/// their allocations, on the same schedule.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Leg {
+    token_id: AccountId,
+    amount: u128,
+    amount_left: u128,
+}
+
+/// Unvested balance of a task deposited into `pool_id`, and the rewards it earned.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Staking {
+    pool_id: AccountId,
+    /// Held by the pool.
+    staked: u128,
+    /// Held by this contract, not staked yet or withdrawn for claims in flight.
+    unstaked: u128,
+    /// Rewards withdrawn per unit of `amount_left`, scaled by `REWARD_PRECISION`.
+    reward_per_token: u128,
+    /// Rewards returned by the pool with `ft_transfer_call` and not shared yet.
+    received: u128,
+    /// Principal asked of the pool and not returned yet.
+    withdrawing: u128,
+}
+
+/// `end_time` of a task was pushed out from `previous_end_time` at `time`.
+#[derive(BorshDeserialize, BorshSerialize)]
+#[derive(Debug, Clone)]
+pub struct Extension {
+    time: u64,
+    previous_end_time: u64,
+}
+
+/// Voting power of an account from `timestamp` on.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Checkpoint {
+    timestamp: u64,
+    power: u128,
+}
+
+/// What is kept of a completed task once it is archived.
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(Debug, Clone)]
+pub struct TaskSummary {
+    token_id: AccountId,
+    start_time: U64,
+    end_time: U64,
+    amount: U128,
+    archived_time: U64,
 }
 
 #[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
 #[serde(crate = "near_sdk::serde")]
 #[derive(Debug, Clone)]
 pub struct ClaimInfo {
+    amount: u128,
     amount_left: u128,
+    base_amount: u128,
+    base_time: u64,
     claim_time: u64,
+    transfer_failed: bool,
+    accepted: bool,
+    /// Rewards of a staking task owed besides what `amount_left` earns from now on.
+    rewards: u128,
+    reward_debt: u128,
+    /// Claimed of each leg of the task.
+    legs_claimed: Vec<u128>,
+    /// Transfers of claims not settled yet, the entry is kept until they are.
+    claims_in_flight: u32,
+    /// Bytes of the entry its beneficiary paid for, refunded to it when it is removed.
+    storage_paid: StorageUsage,
 }
 
 #[ext_contract(ext_fungible_token)]
@@ -76,13 +272,31 @@ pub trait FungibleTokenContract {
         account_id: Option<ValidAccountId>,
         registration_only: Option<bool>,
     ) -> StorageBalance;
+
+    fn storage_balance_of(&self, account_id: ValidAccountId) -> Option<StorageBalance>;
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds;
 }
 
 #[ext_contract(ext_self)]
 pub trait ExtSelf {
-    fn on_claim(&mut self, index: u32, claimer_id: AccountId, amount: U128);
+    fn on_claim(&mut self, index: u32, claimer_id: AccountId, amount: U128, reward: U128, storage_cost: U128);
+
+    fn on_claim_leg(&mut self, index: u32, claimer_id: AccountId, leg_index: u32, amount: U128);
+
+    fn on_storage_checked(&mut self, token_id: AccountId, payer_id: AccountId, deposit: U128);
+
+    fn on_add_token(&mut self, token_id: AccountId, payer_id: AccountId, storage_cost: U128, storage_balance_min: U128);
+
+    fn on_beneficiary_storage_checked(&mut self, token_id: AccountId, account_id: AccountId, payer_id: AccountId, storage_cost: U128);
 
-    fn on_add_token(&mut self, token_id: AccountId);
+    fn on_claimer_storage_checked(&mut self, index: u32, claimer_id: AccountId, amount: U128, deposit: U128, storage_cost: U128);
+
+    fn on_withdraw(&mut self, creator_id: AccountId, token_id: AccountId, amount: U128);
+
+    fn on_staked(&mut self, index: u32, amount: U128);
+
+    fn on_unstaked(&mut self, index: u32, claimer_id: AccountId, amount: U128, withdrawn: U128, from_unstaked: U128, storage_cost: U128);
 }
 
 #[near_bindgen]
@@ -91,29 +305,399 @@ impl Lockup {
     pub fn new(owner_id: AccountId) -> Self {
         Self {
             owner_id,
-            tokens: Vector::new(b't'),
-            tasks: Vector::new(b'a')
+            tokens: UnorderedMap::new(StorageKey::Tokens),
+            tasks: Vector::new(StorageKey::Tasks),
+            storage_accounts: LookupMap::new(StorageKey::StorageAccounts),
+            positions: LookupMap::new(StorageKey::Positions),
+            creator_tasks: LookupMap::new(StorageKey::CreatorTasks),
+            treasuries: LookupMap::new(StorageKey::Treasuries),
+            locked_balances: LookupMap::new(StorageKey::LockedBalances),
+            delegates: LookupMap::new(StorageKey::Delegates),
+            voting_checkpoints: LookupMap::new(StorageKey::VotingCheckpoints),
         }
     }
 
+    /// Registers a token for tasks, open to any creator with a storage deposit, which pays
+    /// for the token record. The attached deposit pays the storage deposit on the token
+    /// contract.
     #[payable]
     pub fn add_token(&mut self, token_id: AccountId) {
+        self.internal_add_token(token_id, env::predecessor_account_id());
+    }
+
+    pub fn remove_token(&mut self, token_id: AccountId) {
+        let sender = env::predecessor_account_id();
+        assert!(sender == self.owner_id, "contract owner only");
+        let mut token = self.tokens.get(&token_id).expect("token not exist");
+        assert!(token.live_tasks == 0, "token still has live tasks");
+        token.tasks.clear();
+        self.tokens.remove(&token_id);
+    }
+
+    /// Sets the TGE of `token_id`, which starts every task of the token waiting for it.
+    pub fn set_tge(&mut self, token_id: AccountId, timestamp: U64) {
         let sender = env::predecessor_account_id();
         assert!(sender == self.owner_id, "contract owner only");
-        self.internal_add_token(token_id);
+        let mut token = self.tokens.get(&token_id).expect("token not exist");
+        assert!(token.tge.is_none(), "tge already set");
+        token.tge = Some(timestamp.into());
+        self.tokens.insert(&token_id, &token);
+    }
+
+    /// Starts task `index`, waiting for the TGE, now.
+    pub fn start_task(&mut self, index: u32) {
+        let sender = env::predecessor_account_id();
+        let mut task = self.internal_get_creator_task(index, &sender);
+        assert!(task.awaiting_tge, "task already started");
+        task.start(env::block_timestamp());
+        self.internal_save_task(index, task);
     }
 
-    pub fn claim(&mut self, token_id: AccountId, index: u32) {
+    /// Replaces a completed task with its summary, freeing its storage. What is left
+    /// of its legs goes back to the creator's treasury.
+    pub fn archive_task(&mut self, index: u32) {
         let sender = env::predecessor_account_id();
-        let task = self.tasks.get(index as u64).unwrap();
+        let initial_storage = env::storage_usage();
+        let task = self.internal_get_creator_task(index, &sender);
+        assert!(task.status == TaskStatus::Completed, "task not completed");
+        assert!(task.account_count == 0, "accounts left, clear them first with clear_accounts");
+        let legacy_claim_amounts: LookupMap<u64, u128> = LookupMap::new(StorageKey::LegacyClaimAmounts);
+        assert!(!legacy_claim_amounts.contains_key(&(index as u64)), "accounts left to migrate");
+        for leg in task.legs.iter() {
+            self.internal_take_back_leg(&task.creator_id, leg);
+        }
+        self.internal_remove_task_index(index, &task);
+        self.tasks.replace(index as u64, &TaskEntry::Archived(TaskSummary {
+            token_id: task.token_id,
+            start_time: task.start_time.into(),
+            end_time: task.end_time.into(),
+            amount: task.amount.into(),
+            archived_time: env::block_timestamp().into(),
+        }));
+        self.internal_update_storage(&sender, initial_storage);
+    }
+
+    /// Removes the entries of `account_ids` in task `index` that are left with nothing
+    /// to claim of any token, paid out merkle allocations, and every entry once the claim
+    /// deadline has passed, along with their positions.
+    pub fn clear_accounts(&mut self, index: u32, account_ids: Vec<AccountId>) {
+        let sender = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let mut task = self.internal_get_creator_task(index, &sender);
+        let expired = task.claim_deadline.map_or(false, |deadline| env::block_timestamp() > deadline + SWEEP_DELAY);
+        assert!(expired || task.status == TaskStatus::Completed, "task not completed");
+        let mut released = 0;
+        for account_id in account_ids {
+            match task.accounts.get(&account_id) {
+                Some(claim_info) if claim_info.claims_in_flight == 0 && (expired || (claim_info.amount_left == 0 && legs_settled(&task, &claim_info))) => {
+                    task.remove_account(&account_id);
+                    self.internal_remove_position(&account_id, index);
+                    if task.voting {
+                        self.internal_unlock(&account_id, &task.token_id, claim_info.amount_left);
+                    }
+                    released += claim_info.storage_paid;
+                    self.internal_refund_storage_deposit(&account_id, claim_info.storage_paid as u128 * env::storage_byte_cost());
+                },
+                _ => continue
+            }
+        }
+        self.internal_save_task(index, task);
+        self.internal_update_storage(&sender, initial_storage - released);
+    }
+
+    /// Adds a chunk of beneficiaries with their own allocation to a draft task.
+    pub fn append_beneficiaries(&mut self, index: u32, beneficiaries: Vec<Beneficiary>) {
+        let sender = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let mut task = self.internal_get_creator_task(index, &sender);
+        assert!(task.status == TaskStatus::Draft, "task not in draft");
+        let claim_count = (task.end_time - task.start_time) / task.vesting_period;
+        let amount_left = task.amount_left;
+        for beneficiary in beneficiaries {
+            let amount: u128 = beneficiary.amount.into();
+            assert!(amount / claim_count as u128 > 0, "{}", TaskError::ZeroClaimAmount);
+            assert!(task.accounts.get(&beneficiary.account_id).is_none(), "{}", TaskError::DuplicateAccount(beneficiary.account_id.clone()));
+            assert!(task.amount_left + amount <= task.amount, "allocations exceed the funded amount");
+            task.allocate(&[beneficiary.account_id.clone()], amount, task.start_time);
+            self.internal_add_position(&beneficiary.account_id, index);
+            if task.voting {
+                self.internal_lock(&beneficiary.account_id, &task.token_id, amount);
+            }
+        }
+        self.internal_add_token_totals(&task.token_id, 0, task.amount_left - amount_left);
+        self.internal_save_task(index, task);
+        self.internal_update_storage(&sender, initial_storage);
+    }
+
+    /// Activates a draft task once its allocations add up to the funded amount.
+    pub fn activate_task(&mut self, index: u32) {
+        let sender = env::predecessor_account_id();
+        let mut task = self.internal_get_creator_task(index, &sender);
+        assert!(task.status == TaskStatus::Draft, "task not in draft");
+        assert!(task.amount_left == task.amount, "allocations don't match the funded amount");
+        task.status = TaskStatus::Active;
+        self.internal_save_task(index, task);
+    }
+
+    /// Registers `account_ids`, beneficiaries of task `index`, with its token, paid from
+    /// the storage deposit of the creator. Accounts already registered are not charged.
+    pub fn register_beneficiaries(&mut self, index: u32, account_ids: Vec<AccountId>) {
+        let sender = env::predecessor_account_id();
+        let task = self.internal_get_creator_task(index, &sender);
+        assert!(account_ids.len() <= MAX_BATCH_REGISTRATIONS, "at most {} accounts can be registered at once", MAX_BATCH_REGISTRATIONS);
+        assert!(account_ids.iter().all(|account_id| task.accounts.get(account_id).is_some()), "not a beneficiary of the task");
+        self.internal_register_beneficiaries(&task.token_id, account_ids, &sender);
+    }
+
+    /// Takes the funding of draft task `index` that is not allocated back into the
+    /// creator's treasury. The draft can then be activated as it is, or is completed if
+    /// nothing was allocated.
+    pub fn cancel_draft(&mut self, index: u32) {
+        let sender = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let mut task = self.internal_get_creator_task(index, &sender);
+        assert!(task.status == TaskStatus::Draft, "task not in draft");
+        let unallocated = task.amount - task.amount_left;
+        assert!(unallocated > 0, "nothing left to cancel");
+        task.amount -= unallocated;
+        let mut token = self.tokens.get(&task.token_id).unwrap();
+        token.amount -= unallocated;
+        if task.amount_left == 0 {
+            task.status = TaskStatus::Completed;
+            token.live_tasks -= 1;
+        }
+        self.tokens.insert(&task.token_id, &token);
+        self.internal_credit_treasury(&sender, &task.token_id, unallocated);
+        self.internal_save_task(index, task);
+        self.internal_update_storage(&sender, initial_storage);
+    }
+
+    /// Pushes out the end of a running task. What is vested so far is kept, and the
+    /// rest vests over the periods left until the new `end_time`.
+    pub fn extend_task(&mut self, index: u32, end_time: U64) {
+        let sender = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let mut task = self.internal_get_creator_task(index, &sender);
+        assert!(!task.awaiting_tge, "task not started");
+        let now = env::block_timestamp();
+        let end_time: u64 = end_time.into();
+        assert!(now < task.end_time, "task already ended");
+        assert!(end_time > task.end_time, "end time should be larger than current end time");
+        assert!(task.claim_deadline.map_or(true, |deadline| end_time < deadline), "{}", TaskError::ClaimDeadlineTooEarly);
+        let time = align_time(&task, now);
+        if task.extensions.last().map(|extension| extension.time) != Some(time) {
+            task.extensions.push(Extension {
+                time,
+                previous_end_time: task.end_time,
+            });
+        }
+        task.end_time = end_time;
+        self.internal_save_task(index, task);
+        self.internal_update_storage(&sender, initial_storage);
+    }
+
+    /// Stops the vesting of `account_id` in task `index`. What is vested so far can
+    /// still be claimed, the rest goes back to the creator's treasury.
+    pub fn revoke(&mut self, index: u32, account_id: AccountId) {
+        let sender = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let mut task = self.internal_get_creator_task(index, &sender);
+        assert!(task.status == TaskStatus::Active, "task not active");
+        assert!(task.staking.is_none(), "{}", TaskError::StakingTaskLocked);
+        assert!(task.legs.is_empty(), "{}", TaskError::LegsTaskLocked);
+        let mut claim_info = task.accounts.get(&account_id).expect("not a beneficiary");
+        let now = env::block_timestamp();
+        let unlocked = get_unlocked_amount(&task, &claim_info, now);
+        let revoked = claim_info.amount - unlocked;
+        assert!(revoked > 0, "nothing left to revoke");
+        claim_info.amount = unlocked;
+        claim_info.amount_left -= revoked;
+        claim_info.base_amount = unlocked;
+        claim_info.base_time = align_time(&task, now);
+        // a task with legs can't be revoked, nothing was paid by the beneficiary
+        self.internal_settle_account(&mut task, index, &account_id, &claim_info);
+        if task.voting {
+            self.internal_unlock(&account_id, &task.token_id, revoked);
+        }
+        self.internal_take_back(&mut task, revoked);
+        self.internal_save_task(index, task);
+        self.internal_update_storage(&sender, initial_storage);
+    }
+
+    /// Accepts the allocation of the caller in task `index`, which can't be claimed
+    /// before if the task requires acceptance.
+    pub fn accept(&mut self, index: u32) {
+        let sender = env::predecessor_account_id();
+        let mut task = self.internal_get_task(index);
+        let deadline = task.acceptance_deadline.expect("task doesn't require acceptance");
+        assert!(env::block_timestamp() <= deadline, "acceptance deadline passed");
+        let mut claim_info = task.accounts.get(&sender).expect("not a beneficiary");
+        assert!(!claim_info.accepted, "already accepted");
+        claim_info.accepted = true;
+        task.accounts.insert(&sender, &claim_info);
+    }
+
+    /// Takes back the allocations of `account_ids` in task `index` that were not
+    /// accepted before the deadline, into the creator's treasury.
+    pub fn reclaim(&mut self, index: u32, account_ids: Vec<AccountId>) {
+        let sender = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let mut task = self.internal_get_creator_task(index, &sender);
+        let deadline = task.acceptance_deadline.expect("task doesn't require acceptance");
+        assert!(env::block_timestamp() > deadline, "acceptance deadline not passed");
+        assert!(task.legs.is_empty(), "{}", TaskError::LegsTaskLocked);
+        let mut reclaimed = 0;
+        for account_id in account_ids {
+            match task.accounts.get(&account_id) {
+                Some(claim_info) if !claim_info.accepted => {
+                    task.remove_account(&account_id);
+                    self.internal_remove_position(&account_id, index);
+                    if task.voting {
+                        self.internal_unlock(&account_id, &task.token_id, claim_info.amount_left);
+                    }
+                    reclaimed += claim_info.amount_left;
+                },
+                _ => continue
+            }
+        }
+        self.internal_take_back(&mut task, reclaimed);
+        self.internal_save_task(index, task);
+        self.internal_update_storage(&sender, initial_storage);
+    }
+
+    /// Transfers what is left unclaimed of task `index`, of each of its tokens, back to
+    /// the creator once the claim deadline has passed and its accounts are cleared.
+    #[payable]
+    pub fn sweep_expired(&mut self, index: u32) {
+        assert_one_yocto();
+        let sender = env::predecessor_account_id();
+        let mut task = self.internal_get_creator_task(index, &sender);
+        let deadline = task.claim_deadline.expect("task has no claim deadline");
+        assert!(env::block_timestamp() > deadline + SWEEP_DELAY, "claim deadline not passed");
+        assert!(task.account_count == 0, "accounts left, expire them first with clear_accounts");
+        assert!(task.amount_left > 0 || task.legs.iter().any(|leg| leg.amount_left > 0), "nothing to sweep");
+        let mut token = self.tokens.get(&task.token_id).unwrap();
+        token.amount_left -= task.amount_left;
+        if task.status != TaskStatus::Completed {
+            task.status = TaskStatus::Completed;
+            token.live_tasks -= 1;
+        }
+        self.tokens.insert(&task.token_id, &token);
+        self.internal_transfer_swept(&sender, &task.token_id, task.amount_left);
+        task.amount_left = 0;
+        for leg in task.legs.iter_mut() {
+            if let Some(mut token) = self.tokens.get(&leg.token_id) {
+                token.amount_left -= leg.amount_left;
+                if leg.amount_left > 0 {
+                    token.live_tasks -= 1;
+                }
+                self.tokens.insert(&leg.token_id, &token);
+            }
+            self.internal_transfer_swept(&sender, &leg.token_id, leg.amount_left);
+            leg.amount_left = 0;
+        }
+        self.internal_save_task(index, task);
+    }
+
+    /// Transfers `amount` of `token_id` out of the caller's treasury, all of it by default.
+    #[payable]
+    pub fn withdraw(&mut self, token_id: AccountId, amount: Option<U128>) -> Promise {
+        assert_one_yocto();
+        let sender = env::predecessor_account_id();
+        let balance = self.treasuries.get(&(sender.clone(), token_id.clone())).unwrap_or(0);
+        let amount = amount.map_or(balance, |amount| amount.into());
+        assert!(amount > 0, "nothing to withdraw");
+        assert!(amount <= balance, "not enough balance");
+        self.treasuries.insert(&(sender.clone(), token_id.clone()), &(balance - amount));
+        ext_fungible_token::ft_transfer(sender.clone(), amount.into(), None, &token_id, 1, GAS_FOR_FT_TRANSFER).then(
+            ext_self::on_withdraw(sender, token_id, amount.into(), &env::current_account_id(), 0, GAS_FOR_ON_WITHDRAW)
+        )
+    }
+
+    /// Claims the vested amount of task `index`. A deposit can be attached to register
+    /// the caller with the token first, any part not needed for that is refunded.
+    /// On the first claim of a merkle task, the caller's `allocation` and its merkle
+    /// `proof` are required, and the caller pays for the storage of its entry.
+    #[payable]
+    pub fn claim(&mut self, token_id: AccountId, index: u32, allocation: Option<U128>, proof: Option<Vec<Base58CryptoHash>>) {
+        let sender = env::predecessor_account_id();
+        let mut deposit = env::attached_deposit();
+        let mut task = self.internal_get_task(index);
+        assert!(task.token_id == token_id, "token not match");
+        assert!(task.status != TaskStatus::Draft, "task not activated");
+        assert!(!task.awaiting_tge, "task not started");
+        assert!(task.claim_deadline.map_or(true, |deadline| env::block_timestamp() < deadline), "claim deadline passed");
+        if let (Some(merkle_root), None) = (task.merkle_root, task.accounts.get(&sender)) {
+            // allocations of a completed task are all paid out, or swept
+            assert!(task.status != TaskStatus::Completed, "task completed");
+            let allocation: u128 = allocation.expect("allocation required").into();
+            let proof: Vec<CryptoHash> = proof.expect("proof required").into_iter().map(|hash| hash.into()).collect();
+            assert!(verify_proof(&merkle_root, leaf_hash(&sender, allocation), &proof), "invalid proof");
+            // the entry is paid by the claimer and given back to it when the entry is removed
+            let initial_storage = env::storage_usage();
+            let mut claim_info = ClaimInfo {
+                amount: allocation,
+                amount_left: allocation,
+                base_amount: 0,
+                base_time: task.start_time,
+                claim_time: task.start_time,
+                transfer_failed: false,
+                accepted: true,
+                rewards: 0,
+                reward_debt: 0,
+                legs_claimed: vec![],
+                claims_in_flight: 0,
+                storage_paid: 0,
+            };
+            task.insert_account(&sender, &claim_info);
+            self.internal_add_position(&sender, index);
+            claim_info.storage_paid = env::storage_usage() - initial_storage;
+            task.accounts.insert(&sender, &claim_info);
+            self.internal_pay_storage(&sender, claim_info.storage_paid, &mut deposit);
+            if task.voting {
+                // the voting checkpoint of the lock is not given back, like the one of a claim
+                let initial_storage = env::storage_usage();
+                self.internal_lock(&sender, &token_id, allocation);
+                self.internal_pay_storage(&sender, env::storage_usage() - initial_storage, &mut deposit);
+            }
+            self.internal_save_task(index, task);
+            task = self.internal_get_task(index);
+        }
         assert!(task.accounts.get(&sender).is_some(), "not allowed to claim");
-        let claim_info = task.accounts.get(&sender).unwrap();
-        let amount = get_claim_amount(&task, &claim_info);
-        if u128::from(amount) > 0  {
-            ext_fungible_token::ft_transfer(sender.clone(), amount.into(), None, &token_id, 1, env::prepaid_gas() / 3).then(
-                ext_self::on_claim(index, sender, amount, &env::current_account_id(), 0, env::prepaid_gas() / 3)
+        let mut claim_info = task.accounts.get(&sender).unwrap();
+        assert!(claim_info.accepted, "allocation not accepted");
+        let amount: u128 = get_claim_amount(&task, &claim_info).into();
+        let leg_amounts = get_leg_claim_amounts_at(&task, &claim_info, env::block_timestamp());
+        if claim_info.legs_claimed.len() < task.legs.len() && leg_amounts.iter().any(|leg_amount| *leg_amount > 0) {
+            // room for the claims of the legs, paid by the claimer
+            let usage = (task.legs.len() - claim_info.legs_claimed.len()) as StorageUsage * LEG_CLAIM_STORAGE_USAGE;
+            claim_info.legs_claimed.resize(task.legs.len(), 0);
+            claim_info.storage_paid += usage;
+            self.internal_pay_storage(&sender, usage, &mut deposit);
+        }
+        // recorded before anything is transferred, the callbacks only give back what fails
+        self.internal_debit_claim(&mut task, &mut claim_info, amount, &leg_amounts);
+        task.accounts.insert(&sender, &claim_info);
+        let leg_token_ids: Vec<AccountId> = task.legs.iter().map(|leg| leg.token_id.clone()).collect();
+        let voting = task.voting;
+        self.internal_save_task(index, task);
+        for (leg_index, leg_amount) in leg_amounts.into_iter().enumerate().filter(|(_, leg_amount)| *leg_amount > 0) {
+            self.internal_transfer_leg_claim(&leg_token_ids[leg_index], index, &sender, leg_index as u32, leg_amount);
+        }
+        if amount == 0 {
+            if deposit > 0 {
+                Promise::new(sender).transfer(deposit);
+            }
+            return;
+        }
+        // only the claims of a voting task write a checkpoint
+        let storage_cost = if voting { self.internal_pay_storage(&sender, CLAIM_STORAGE_USAGE, &mut deposit) } else { 0 };
+        if deposit > 0 {
+            ext_fungible_token::storage_balance_of(sender.clone().try_into().unwrap(), &token_id, 0, GAS_FOR_FT_VIEW).then(
+                ext_self::on_claimer_storage_checked(index, sender, amount.into(), deposit.into(), storage_cost.into(), &env::current_account_id(), 0, GAS_FOR_ON_CLAIMER_STORAGE_CHECKED)
             );
+        } else {
+            self.internal_transfer_claim(&token_id, index, sender, amount.into(), storage_cost);
         }
-        
     }
 }
\ No newline at end of file
@@ -0,0 +1,34 @@
+use crate::*;
+use near_sdk::CryptoHash;
+
+/// Preimage of the `(account_id, amount)` leaf of a merkle task:
+/// `0x00 || account_id || amount as u128 little endian`.
+pub fn leaf_data(account_id: &AccountId, amount: u128) -> Vec<u8> {
+    let mut data = vec![0u8];
+    data.extend_from_slice(account_id.as_bytes());
+    data.extend_from_slice(&amount.to_le_bytes());
+    data
+}
+
+/// Preimage of an inner node: `0x01 || min(left, right) || max(left, right)`.
+/// Children are sorted so that proofs don't need to tell the side of each sibling.
+pub fn node_data(left: &CryptoHash, right: &CryptoHash) -> Vec<u8> {
+    let (first, second) = if left <= right { (left, right) } else { (right, left) };
+    let mut data = vec![1u8];
+    data.extend_from_slice(first);
+    data.extend_from_slice(second);
+    data
+}
+
+pub fn leaf_hash(account_id: &AccountId, amount: u128) -> CryptoHash {
+    sha256(&leaf_data(account_id, amount))
+}
+
+pub fn verify_proof(root: &CryptoHash, leaf: CryptoHash, proof: &[CryptoHash]) -> bool {
+    let hash = proof.iter().fold(leaf, |hash, sibling| sha256(&node_data(&hash, sibling)));
+    hash == *root
+}
+
+fn sha256(data: &[u8]) -> CryptoHash {
+    env::sha256(data).try_into().unwrap()
+}
@@ -0,0 +1,148 @@
+use crate::*;
+
+/// State layout before storage keys were introduced, where each task keyed its
+/// accounts by `token_id + index`.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OldLockup {
+    owner_id: AccountId,
+    tokens: Vector<AccountId>,
+    tasks: Vector<OldTask>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OldTask {
+    token_id: AccountId,
+    accounts: LookupMap<AccountId, OldClaimInfo>,
+    start_time: u64,
+    end_time: u64,
+    vesting_period: u64,
+    amount: u128,
+    single_claim_amount: u128,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OldClaimInfo {
+    amount_left: u128,
+    claim_time: u64,
+}
+
+#[near_bindgen]
+impl Lockup {
+    /// Moves the old state to the current layout. Tasks keep their schedule but start
+    /// with no accounts, which are moved over by `migrate_task_accounts`. The owner
+    /// becomes the creator of every old task. A state already in the current layout,
+    /// as when the same code is deployed again, is kept as it is.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let state = env::storage_read(b"STATE").expect("failed to read old state");
+        if let Ok(this) = Self::try_from_slice(&state) {
+            return this;
+        }
+        let mut old = OldLockup::try_from_slice(&state).expect("failed to read old state");
+        let mut this = Self {
+            owner_id: old.owner_id.clone(),
+            tokens: UnorderedMap::new(StorageKey::Tokens),
+            tasks: Vector::new(StorageKey::Tasks),
+            storage_accounts: LookupMap::new(StorageKey::StorageAccounts),
+            positions: LookupMap::new(StorageKey::Positions),
+            creator_tasks: LookupMap::new(StorageKey::CreatorTasks),
+            treasuries: LookupMap::new(StorageKey::Treasuries),
+            locked_balances: LookupMap::new(StorageKey::LockedBalances),
+            delegates: LookupMap::new(StorageKey::Delegates),
+            voting_checkpoints: LookupMap::new(StorageKey::VotingCheckpoints),
+        };
+        let mut legacy_claim_amounts: LookupMap<u64, u128> = LookupMap::new(StorageKey::LegacyClaimAmounts);
+        for token_id in old.tokens.iter() {
+            this.tokens.insert(&token_id, &Token::new(&token_id, None, 0));
+        }
+        for old_task in old.tasks.iter() {
+            let index = this.tasks.len();
+            legacy_claim_amounts.insert(&index, &old_task.single_claim_amount);
+            if let Some(mut token) = this.tokens.get(&old_task.token_id) {
+                token.live_tasks += 1;
+                token.tasks.insert(&(index as u32));
+                token.amount += old_task.amount;
+                this.tokens.insert(&old_task.token_id, &token);
+            }
+            this.internal_add_creator_task(&old.owner_id, index as u32);
+            this.tasks.push(&TaskEntry::Task(Task {
+                creator_id: old.owner_id.clone(),
+                token_id: old_task.token_id,
+                accounts: LookupMap::new(StorageKey::TaskAccounts { index }),
+                start_time: old_task.start_time,
+                end_time: old_task.end_time,
+                vesting_period: old_task.vesting_period,
+                amount: old_task.amount,
+                amount_left: 0,
+                status: TaskStatus::Active,
+                extensions: vec![],
+                merkle_root: None,
+                acceptance_deadline: None,
+                staking: None,
+                legs: vec![],
+                claim_deadline: None,
+                awaiting_tge: false,
+                account_count: 0,
+                voting: false,
+            }));
+        }
+        old.tokens.clear();
+        old.tasks.clear();
+        this
+    }
+
+    /// Deploys the new code passed as raw input and migrates the state with its `migrate`.
+    /// Only the factory this contract was created by, its parent account, can upgrade it.
+    pub fn upgrade(&self) -> Promise {
+        let current_account_id = env::current_account_id();
+        let factory_id = current_account_id.splitn(2, '.').nth(1).unwrap_or_default();
+        assert!(env::predecessor_account_id() == factory_id, "factory only");
+        let code = env::input().expect("code required");
+        Promise::new(current_account_id).deploy_contract(code).function_call(b"migrate".to_vec(), vec![], 0, GAS_FOR_MIGRATE)
+    }
+
+    /// Moves the given accounts of a migrated task from the old `token_id + index`
+    /// prefix to the task's own storage key. A task completed by claims made before all
+    /// its accounts are moved is active again. `last` marks the final batch of the task.
+    pub fn migrate_task_accounts(&mut self, index: u32, account_ids: Vec<AccountId>, last: bool) {
+        let sender = env::predecessor_account_id();
+        assert!(sender == self.owner_id, "contract owner only");
+        let mut task = self.internal_get_task(index);
+        let key_prefix = task.token_id.clone() + &index.to_string();
+        let mut old_accounts: LookupMap<AccountId, OldClaimInfo> = LookupMap::new(key_prefix.into_bytes());
+        let mut legacy_claim_amounts: LookupMap<u64, u128> = LookupMap::new(StorageKey::LegacyClaimAmounts);
+        let single_claim_amount = legacy_claim_amounts.get(&(index as u64)).expect("task accounts migrated");
+        let amount_left = task.amount_left;
+        for account_id in account_ids {
+            if let Some(old_claim_info) = old_accounts.remove(&account_id).filter(|old_claim_info| old_claim_info.amount_left > 0) {
+                // old tasks unlocked `single_claim_amount` per period, which gives back the allocation
+                let claimed_count = (old_claim_info.claim_time - task.start_time) / task.vesting_period;
+                task.amount_left += old_claim_info.amount_left;
+                task.insert_account(&account_id, &ClaimInfo {
+                    amount: old_claim_info.amount_left + single_claim_amount * claimed_count as u128,
+                    amount_left: old_claim_info.amount_left,
+                    base_amount: 0,
+                    base_time: task.start_time,
+                    claim_time: old_claim_info.claim_time,
+                    transfer_failed: false,
+                    accepted: true,
+                    rewards: 0,
+                    reward_debt: 0,
+                    legs_claimed: vec![],
+                    claims_in_flight: 0,
+                    storage_paid: 0,
+                });
+                self.internal_add_position(&account_id, index);
+            }
+        }
+        if task.amount_left > amount_left && self.tokens.get(&task.token_id).is_some() {
+            self.internal_reactivate_task(&mut task);
+            self.internal_add_token_totals(&task.token_id, 0, task.amount_left - amount_left);
+        }
+        if last {
+            legacy_claim_amounts.remove(&(index as u64));
+        }
+        self.internal_save_task(index, task);
+    }
+}
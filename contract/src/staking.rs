@@ -0,0 +1,164 @@
+use crate::*;
+use near_sdk::{PromiseResult, serde_json};
+use crate::utils::U256;
+
+/// Scale of `Staking::reward_per_token`.
+const REWARD_PRECISION: u128 = 1_000_000_000_000_000_000;
+
+/// Pool a staking task deposits its unvested balance into. Stakes are keyed by task index.
+#[ext_contract(ext_staking_pool)]
+pub trait StakingPool {
+    fn withdraw(&mut self, key: String, amount: U128) -> U128;
+}
+
+impl Staking {
+    pub(crate) fn new(pool_id: AccountId, amount: u128) -> Self {
+        Self {
+            pool_id,
+            staked: 0,
+            unstaked: amount,
+            reward_per_token: 0,
+            received: 0,
+            withdrawing: 0,
+        }
+    }
+
+    /// Rewards earned by `amount_left` since the task was created.
+    pub(crate) fn accrued(&self, amount_left: u128) -> u128 {
+        (U256::from(amount_left) * U256::from(self.reward_per_token) / U256::from(REWARD_PRECISION)).as_u128()
+    }
+
+    /// Shares `reward` between beneficiaries in proportion to what they have left,
+    /// `amount_left` in total.
+    fn distribute(&mut self, reward: u128, amount_left: u128) {
+        if reward > 0 && amount_left > 0 {
+            self.reward_per_token += (U256::from(reward) * U256::from(REWARD_PRECISION) / U256::from(amount_left)).as_u128();
+        }
+    }
+
+    /// Rewards withdrawn from the pool so far that are owed to `claim_info`.
+    pub(crate) fn pending_rewards(&self, claim_info: &ClaimInfo) -> u128 {
+        claim_info.rewards + self.accrued(claim_info.amount_left) - claim_info.reward_debt
+    }
+}
+
+impl Lockup {
+    /// Deposits what task `index` holds out of its staking pool into it.
+    pub(crate) fn internal_stake(&mut self, index: u32) {
+        let mut task = self.internal_get_task(index);
+        let staking = task.staking.as_mut().unwrap();
+        let amount = staking.unstaked;
+        staking.staked += amount;
+        staking.unstaked = 0;
+        let pool_id = staking.pool_id.clone();
+        let token_id = task.token_id.clone();
+        self.internal_save_task(index, task);
+        ext_fungible_token::ft_transfer_call(pool_id, amount.into(), None, index.to_string(), &token_id, 1, GAS_FOR_STAKE).then(
+            ext_self::on_staked(index, amount.into(), &env::current_account_id(), 0, GAS_FOR_ON_STAKED)
+        );
+    }
+
+    /// Withdraws what the claim of `amount` needs from the staking pool of task `index`,
+    /// along with the rewards of the task, then transfers the claim.
+    pub(crate) fn internal_unstake_and_claim(&mut self, index: u32, claimer_id: AccountId, amount: U128, storage_cost: u128) -> Promise {
+        let mut task = self.internal_get_task(index);
+        let staking = task.staking.as_mut().unwrap();
+        let from_unstaked = std::cmp::min(amount.into(), staking.unstaked);
+        let withdrawn = u128::from(amount) - from_unstaked;
+        staking.unstaked -= from_unstaked;
+        staking.staked -= withdrawn;
+        staking.withdrawing += withdrawn;
+        let pool_id = staking.pool_id.clone();
+        self.internal_save_task(index, task);
+        ext_staking_pool::withdraw(index.to_string(), withdrawn.into(), &pool_id, 0, GAS_FOR_POOL_WITHDRAW).then(
+            ext_self::on_unstaked(index, claimer_id, amount, withdrawn.into(), from_unstaked.into(), storage_cost.into(), &env::current_account_id(), 0, GAS_FOR_ON_UNSTAKED)
+        )
+    }
+
+    /// Takes in `amount` of `token_id` that `pool_id` returned of the stake `stake_key`.
+    /// What the pool still owes of the principal asked of it is counted first, only the
+    /// rest is reward.
+    pub(crate) fn internal_receive_unstaked(&mut self, pool_id: &AccountId, token_id: &AccountId, stake_key: &str, amount: u128) -> Result<(), TaskError> {
+        let index: u32 = stake_key.parse().map_err(|_| TaskError::UnknownStake)?;
+        let mut task = match self.tasks.get(index as u64) {
+            Some(TaskEntry::Task(task)) => task,
+            _ => return Err(TaskError::UnknownStake)
+        };
+        match task.staking.as_mut() {
+            Some(staking) if staking.pool_id == *pool_id && task.token_id == *token_id => {
+                let principal = std::cmp::min(amount, staking.withdrawing);
+                staking.withdrawing -= principal;
+                staking.unstaked += principal;
+                staking.received += amount - principal;
+            },
+            _ => return Err(TaskError::UnknownStake)
+        }
+        self.internal_save_task(index, task);
+        Ok(())
+    }
+}
+
+#[near_bindgen]
+impl Lockup {
+    /// Takes back into the task whatever the pool didn't accept of the `amount` staked.
+    #[private]
+    pub fn on_staked(&mut self, index: u32, amount: U128) {
+        let used: u128 = match env::promise_result(0) {
+            PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value).map_or(0, |used| used.into()),
+            _ => 0
+        };
+        let refunded = u128::from(amount) - used;
+        if refunded > 0 {
+            log!("failed to stake {} of task {}", refunded, index);
+            let mut task = self.internal_get_task(index);
+            let staking = task.staking.as_mut().unwrap();
+            staking.staked -= refunded;
+            staking.unstaked += refunded;
+            self.internal_save_task(index, task);
+        }
+    }
+
+    /// Shares the rewards that came with the withdrawal between the beneficiaries of
+    /// task `index`, then transfers the claim along with the claimer's rewards. Only what
+    /// the pool actually returned counts, not what it reports. The claim is given back
+    /// if the withdrawal failed, or if what was withdrawn hasn't arrived yet, in which case
+    /// it is taken in whenever it does.
+    #[private]
+    pub fn on_unstaked(&mut self, index: u32, claimer_id: AccountId, amount: U128, withdrawn: U128, from_unstaked: U128, storage_cost: U128) {
+        let amount: u128 = amount.into();
+        let withdrawn: u128 = withdrawn.into();
+        let failed = !matches!(env::promise_result(0), PromiseResult::Successful(_));
+        let mut task = self.internal_get_task(index);
+        let mut claim_info = task.accounts.get(&claimer_id).unwrap();
+        let staking = task.staking.as_mut().unwrap();
+        if withdrawn > 0 && (failed || staking.unstaked < withdrawn) {
+            if failed {
+                log!("failed to withdraw {} of task {} from {}", withdrawn, index, staking.pool_id);
+                staking.withdrawing = staking.withdrawing.saturating_sub(withdrawn);
+                staking.staked += withdrawn;
+            } else {
+                // still owed by the pool, `withdrawing` takes it in when it arrives
+                log!("{} of task {} not returned yet by {}", withdrawn, index, staking.pool_id);
+            }
+            staking.unstaked += u128::from(from_unstaked);
+            claim_info.claims_in_flight -= 1;
+            self.internal_restore_claim(&mut task, &mut claim_info, amount, 0);
+            task.accounts.insert(&claimer_id, &claim_info);
+            self.internal_save_task(index, task);
+            self.internal_refund_storage_deposit(&claimer_id, storage_cost.into());
+            return;
+        }
+        staking.unstaked -= withdrawn;
+        let reward = staking.received;
+        staking.received = 0;
+        // the claim was taken off `amount_left` but earned its part of the reward
+        let share = (U256::from(reward) * U256::from(amount) / U256::from(task.amount_left + amount)).as_u128();
+        staking.distribute(reward - share, task.amount_left);
+        let reward = claim_info.rewards + share;
+        claim_info.rewards = 0;
+        task.accounts.insert(&claimer_id, &claim_info);
+        let token_id = task.token_id.clone();
+        self.internal_save_task(index, task);
+        self.internal_send_claim(&token_id, index, claimer_id, amount.into(), reward, storage_cost.into());
+    }
+}
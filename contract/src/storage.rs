@@ -0,0 +1,191 @@
+use crate::*;
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::{assert_one_yocto, Balance, StorageUsage};
+
+/// Bytes reserved for the storage account record itself.
+const ACCOUNT_STORAGE_USAGE: StorageUsage = 200;
+
+/// Bytes the claimer pays for in advance for the voting checkpoint of a claim, what is
+/// not used is refunded.
+pub(crate) const CLAIM_STORAGE_USAGE: StorageUsage = 200;
+
+/// Bytes a token record takes at most, its icon being capped to `MAX_ICON_LENGTH`.
+pub(crate) const TOKEN_STORAGE_USAGE: StorageUsage = 1500;
+
+/// Bytes each leg adds to the entry of a beneficiary once it claims the leg.
+pub(crate) const LEG_CLAIM_STORAGE_USAGE: StorageUsage = 16;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct StorageAccount {
+    deposit: Balance,
+    used: StorageUsage,
+}
+
+impl StorageAccount {
+    fn available(&self) -> Balance {
+        self.deposit - self.used as Balance * env::storage_byte_cost()
+    }
+}
+
+impl Lockup {
+    /// Charges `account_id` for the storage added since `initial_storage`, or credits
+    /// back the storage that was freed.
+    pub(crate) fn internal_update_storage(&mut self, account_id: &AccountId, initial_storage: StorageUsage) {
+        let storage = env::storage_usage();
+        let mut account = match self.storage_accounts.get(account_id) {
+            Some(account) => account,
+            None => {
+                assert!(storage <= initial_storage, "storage deposit required");
+                return;
+            }
+        };
+        if storage > initial_storage {
+            account.used += storage - initial_storage;
+            assert!(
+                account.used as Balance * env::storage_byte_cost() <= account.deposit,
+                "not enough storage deposit, {} required",
+                account.used as Balance * env::storage_byte_cost()
+            );
+        } else {
+            account.used = std::cmp::max(account.used.saturating_sub(initial_storage - storage), ACCOUNT_STORAGE_USAGE);
+        }
+        self.storage_accounts.insert(account_id, &account);
+    }
+
+    /// Asserts the storage deposit of `account_id` has enough available for `usage` bytes.
+    pub(crate) fn internal_assert_storage_available(&self, account_id: &AccountId, usage: StorageUsage) {
+        let account = self.storage_accounts.get(account_id).expect("storage deposit required");
+        let cost = usage as Balance * env::storage_byte_cost();
+        assert!(account.available() >= cost, "not enough storage deposit, {} required", cost);
+    }
+
+    /// Takes `amount` out of the storage deposit of `account_id` to pay storage on
+    /// other contracts.
+    pub(crate) fn internal_spend_storage_deposit(&mut self, account_id: &AccountId, amount: Balance) {
+        let mut account = self.storage_accounts.get(account_id).expect("storage deposit required");
+        assert!(account.available() >= amount, "not enough storage deposit, {} required", amount);
+        account.deposit -= amount;
+        self.storage_accounts.insert(account_id, &account);
+    }
+
+    /// Charges `account_id` for `usage` bytes, from its storage deposit if enough of it is
+    /// available, or else from `deposit` attached to the call. Returns the cost.
+    pub(crate) fn internal_pay_storage(&mut self, account_id: &AccountId, usage: StorageUsage, deposit: &mut Balance) -> Balance {
+        let cost = usage as Balance * env::storage_byte_cost();
+        match self.storage_accounts.get(account_id) {
+            Some(mut account) if account.available() >= cost => {
+                account.deposit -= cost;
+                self.storage_accounts.insert(account_id, &account);
+            },
+            _ => {
+                assert!(*deposit >= cost, "not enough deposit for the storage of the claim, {} required", cost);
+                *deposit -= cost;
+            }
+        }
+        cost
+    }
+
+    pub(crate) fn internal_refund_storage_deposit(&mut self, account_id: &AccountId, amount: Balance) {
+        if amount == 0 {
+            return;
+        }
+        match self.storage_accounts.get(account_id) {
+            Some(mut account) => {
+                account.deposit += amount;
+                self.storage_accounts.insert(account_id, &account);
+            },
+            None => {
+                Promise::new(account_id.clone()).transfer(amount);
+            }
+        }
+    }
+
+    fn internal_storage_balance_of(&self, account_id: &AccountId) -> Option<StorageBalance> {
+        self.storage_accounts.get(account_id).map(|account| StorageBalance {
+            total: account.deposit.into(),
+            available: account.available().into(),
+        })
+    }
+}
+
+#[near_bindgen]
+impl StorageManagement for Lockup {
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<ValidAccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let amount = env::attached_deposit();
+        let account_id = account_id.map(|account_id| account_id.into()).unwrap_or_else(env::predecessor_account_id);
+        let registration_only = registration_only.unwrap_or(false);
+        let min_balance = self.storage_balance_bounds().min.0;
+        let (mut account, refund) = match self.storage_accounts.get(&account_id) {
+            Some(account) => {
+                if registration_only {
+                    log!("already registered, refunding the deposit");
+                    (account, amount)
+                } else {
+                    (account, 0)
+                }
+            },
+            None => {
+                assert!(amount >= min_balance, "deposit is less than the minimum storage balance");
+                let account = StorageAccount {
+                    deposit: 0,
+                    used: ACCOUNT_STORAGE_USAGE,
+                };
+                (account, if registration_only { amount - min_balance } else { 0 })
+            }
+        };
+        account.deposit += amount - refund;
+        self.storage_accounts.insert(&account_id, &account);
+        if refund > 0 {
+            Promise::new(env::predecessor_account_id()).transfer(refund);
+        }
+        self.internal_storage_balance_of(&account_id).unwrap()
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut account = self.storage_accounts.get(&account_id).expect("not registered");
+        let available = account.available();
+        let amount = amount.map(u128::from).unwrap_or(available);
+        assert!(amount <= available, "amount exceeds available storage balance");
+        account.deposit -= amount;
+        self.storage_accounts.insert(&account_id, &account);
+        if amount > 0 {
+            Promise::new(account_id.clone()).transfer(amount);
+        }
+        self.internal_storage_balance_of(&account_id).unwrap()
+    }
+
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        assert!(!force.unwrap_or(false), "force unregister is not supported");
+        let account_id = env::predecessor_account_id();
+        match self.storage_accounts.get(&account_id) {
+            Some(account) => {
+                assert!(account.used == ACCOUNT_STORAGE_USAGE, "storage is still in use");
+                self.storage_accounts.remove(&account_id);
+                Promise::new(account_id).transfer(account.deposit);
+                true
+            },
+            None => false
+        }
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: (ACCOUNT_STORAGE_USAGE as Balance * env::storage_byte_cost()).into(),
+            max: None,
+        }
+    }
+
+    fn storage_balance_of(&self, account_id: ValidAccountId) -> Option<StorageBalance> {
+        self.internal_storage_balance_of(account_id.as_ref())
+    }
+}
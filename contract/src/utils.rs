@@ -1,20 +1,142 @@
+use crate::*;
+use uint::construct_uint;
 
-use near_sdk::{log};
+construct_uint! {
+    pub struct U256(4);
+}
 
-use crate::*;
+/// Start of the vesting period of `task` that `timestamp` falls in.
+pub(crate) fn align_time(task: &Task, timestamp: u64) -> u64 {
+    if timestamp <= task.start_time {
+        return task.start_time;
+    }
+    timestamp - (timestamp - task.start_time) % task.vesting_period
+}
+
+/// Amount vested at `timestamp` of an allocation where `base_amount` was vested at
+/// `base_time` and the rest unlocks in equal parts each period until `end_time`.
+pub(crate) fn vested_amount(amount: u128, base_amount: u128, base_time: u64, end_time: u64, vesting_period: u64, timestamp: u64) -> u128 {
+    if timestamp >= end_time {
+        return amount;
+    }
+    let claim_count = (end_time.saturating_sub(base_time) / vesting_period) as u128;
+    if timestamp < base_time || claim_count == 0 {
+        return base_amount;
+    }
+    let present_claim_count = ((timestamp - base_time) / vesting_period) as u128;
+    // the product doesn't fit in u128 for large amounts over many periods
+    let vested = U256::from(amount - base_amount) * U256::from(present_claim_count) / U256::from(claim_count);
+    base_amount + vested.as_u128()
+}
+
+/// Amount of `claim_info` vested at `timestamp`, claimed or not. Each extension of the
+/// task made after the allocation was last changed starts a new schedule from what was
+/// vested at that time.
+pub(crate) fn get_unlocked_amount(task: &Task, claim_info: &ClaimInfo, timestamp: u64) -> u128 {
+    // allocations made while the task waited for the TGE vest from its actual start
+    let allocation_time = if claim_info.base_time == PENDING_START_TIME { task.start_time } else { claim_info.base_time };
+    let mut base_amount = claim_info.base_amount;
+    let mut base_time = allocation_time;
+    for extension in task.extensions.iter().filter(|extension| extension.time > allocation_time) {
+        if timestamp < extension.time {
+            return vested_amount(claim_info.amount, base_amount, base_time, extension.previous_end_time, task.vesting_period, timestamp);
+        }
+        base_amount = vested_amount(claim_info.amount, base_amount, base_time, extension.previous_end_time, task.vesting_period, extension.time);
+        base_time = extension.time;
+    }
+    vested_amount(claim_info.amount, base_amount, base_time, task.end_time, task.vesting_period, timestamp)
+}
+
+/// Whether anything of `task` can be claimed at `timestamp`: it is activated and started,
+/// and its claim deadline, if any, has not passed.
+fn is_claimable(task: &Task, timestamp: u64) -> bool {
+    task.status != TaskStatus::Draft && !task.awaiting_tge && task.claim_deadline.map_or(true, |deadline| timestamp < deadline)
+}
 
+/// Amount `claim_info` can claim at `timestamp`, given what it has claimed so far.
+pub(crate) fn get_claim_amount_at(task: &Task, claim_info: &ClaimInfo, timestamp: u64) -> u128 {
+    if !is_claimable(task, timestamp) {
+        return 0;
+    }
+    let unlocked = get_unlocked_amount(task, claim_info, timestamp);
+    let claimed = claim_info.amount - claim_info.amount_left;
+    unlocked.saturating_sub(claimed)
+}
+
+/// Amount `claim_info` can claim now.
 pub(crate) fn get_claim_amount(task: &Task, claim_info: &ClaimInfo) -> U128 {
-    let claim_duration = claim_info.claim_time - task.start_time;
-    let claim_count = claim_duration / task.vesting_period;
+    get_claim_amount_at(task, claim_info, env::block_timestamp()).into()
+}
 
-    let present_duration = env::block_timestamp() - task.start_time;
-    let present_claim_count = present_duration / task.vesting_period;
+/// Part of leg `leg_index` of `task` that goes with `amount` of its own token.
+fn leg_share(task: &Task, leg_index: usize, amount: u128) -> u128 {
+    (U256::from(amount) * U256::from(task.legs[leg_index].amount) / U256::from(task.amount)).as_u128()
+}
 
-    let count = present_claim_count - claim_count;
-    let mut claim_amount = count  as u128 * task.single_claim_amount;
+/// Amounts of each leg of `task` that `claim_info` can claim at `timestamp`, in
+/// proportion to what it unlocked of the task's own token.
+pub(crate) fn get_leg_claim_amounts_at(task: &Task, claim_info: &ClaimInfo, timestamp: u64) -> Vec<u128> {
+    if !is_claimable(task, timestamp) {
+        return vec![0; task.legs.len()];
+    }
+    let unlocked = get_unlocked_amount(task, claim_info, timestamp);
+    (0..task.legs.len()).map(|leg_index| {
+        let claimed = claim_info.legs_claimed.get(leg_index).copied().unwrap_or(0);
+        leg_share(task, leg_index, unlocked).saturating_sub(claimed)
+    }).collect()
+}
 
-    if env::block_timestamp() >= task.end_time{
-        claim_amount = claim_info.amount_left;
+/// Whether `claim_info` claimed all it gets of every leg of `task`.
+pub(crate) fn legs_settled(task: &Task, claim_info: &ClaimInfo) -> bool {
+    (0..task.legs.len()).all(|leg_index| {
+        claim_info.legs_claimed.get(leg_index).copied().unwrap_or(0) >= leg_share(task, leg_index, claim_info.amount)
+    })
+}
+
+/// First time after `timestamp` that `vested_amount` of the same schedule increases,
+/// None once everything is vested.
+fn next_vesting_time(amount: u128, base_amount: u128, base_time: u64, end_time: u64, vesting_period: u64, timestamp: u64) -> Option<u64> {
+    let vested = vested_amount(amount, base_amount, base_time, end_time, vesting_period, timestamp);
+    if vested >= amount {
+        return None;
+    }
+    let claim_count = end_time.saturating_sub(base_time) / vesting_period;
+    if claim_count == 0 {
+        return Some(end_time);
+    }
+    // fewest periods whose share of the rest is more than what is vested of it now
+    let rest = U256::from(amount - base_amount);
+    let count = (U256::from(vested - base_amount + 1) * U256::from(claim_count) + rest - 1) / rest;
+    Some(std::cmp::min(base_time + count.as_u64() * vesting_period, end_time))
+}
+
+/// First time after `timestamp` that more of `claim_info` unlocks, and the amount
+/// unlocked then. None once everything is unlocked, or while the task is a draft or
+/// waits for the TGE.
+pub(crate) fn get_next_unlock(task: &Task, claim_info: &ClaimInfo, timestamp: u64) -> Option<(u64, u128)> {
+    if task.status == TaskStatus::Draft || task.awaiting_tge {
+        return None;
+    }
+    let unlocked = get_unlocked_amount(task, claim_info, timestamp);
+    if unlocked >= claim_info.amount {
+        return None;
+    }
+    let unlock_at = |time: u64| Some((time, get_unlocked_amount(task, claim_info, time) - unlocked));
+    // same schedules as `get_unlocked_amount`, each one until the extension that ends it
+    let allocation_time = if claim_info.base_time == PENDING_START_TIME { task.start_time } else { claim_info.base_time };
+    let mut base_amount = claim_info.base_amount;
+    let mut base_time = allocation_time;
+    let mut from = timestamp;
+    for extension in task.extensions.iter().filter(|extension| extension.time > allocation_time) {
+        if from < extension.time {
+            let time = next_vesting_time(claim_info.amount, base_amount, base_time, extension.previous_end_time, task.vesting_period, from);
+            if let Some(time) = time.filter(|time| *time <= extension.time) {
+                return unlock_at(time);
+            }
+            from = extension.time;
+        }
+        base_amount = vested_amount(claim_info.amount, base_amount, base_time, extension.previous_end_time, task.vesting_period, extension.time);
+        base_time = extension.time;
     }
-    claim_amount.into()
-}
\ No newline at end of file
+    next_vesting_time(claim_info.amount, base_amount, base_time, task.end_time, task.vesting_period, from).and_then(unlock_at)
+}
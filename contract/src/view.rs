@@ -1,6 +1,18 @@
 
 use crate::*;
 
+/// Version of the JSON interface described in `abi.json`, bumped on any change to the
+/// arguments or results of public methods.
+pub const SCHEMA_VERSION: u32 = 17;
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(Debug, Clone)]
+pub struct ContractInfo {
+  schema_version: u32,
+  owner_id: AccountId
+}
+
 #[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
 #[serde(crate = "near_sdk::serde")]
 #[derive(Debug, Clone)]
@@ -8,58 +20,291 @@ pub struct Claim {
     amount_left: U128,
     claim_time: U64,
     should_claim: U128,
-    index: u32
+    index: u32,
+    error: Option<String>,
+    next_unlock_time: Option<U64>,
+    next_unlock_amount: Option<U128>,
+    token_id: AccountId,
+    start_time: U64,
+    end_time: U64,
+    vesting_period: U64,
+    amount: U128,
+    accepted: bool,
+    rewards: U128,
+    legs: Vec<LegClaim>
+}
+
+/// What a beneficiary can claim of a leg of its task.
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(Debug, Clone)]
+pub struct LegClaim {
+    token_id: AccountId,
+    should_claim: U128
 }
 
 #[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
 #[serde(crate = "near_sdk::serde")]
 #[derive(Debug, Clone)]
 pub struct TaskInfo {
+    creator_id: AccountId,
     token_id: AccountId,
-    start_time: u64,
-    end_time: u64,
-    vesting_period: u64,
-    amount: u128,
-    single_claim_amount: u128,
+    start_time: U64,
+    end_time: U64,
+    vesting_period: U64,
+    amount: U128,
+    status: TaskStatus,
+    merkle_root: Option<Base58CryptoHash>,
+    acceptance_deadline: Option<U64>,
+    staking_pool: Option<AccountId>,
+    legs: Vec<LegInfo>,
+    claim_deadline: Option<U64>,
+    awaiting_tge: bool,
+    voting: bool,
     index: u32
 }
 
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(Debug, Clone)]
+pub struct LegInfo {
+    token_id: AccountId,
+    amount: U128,
+    amount_left: U128
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(Debug, Clone)]
+pub struct TokenInfo {
+  token_id: AccountId,
+  metadata: Option<TokenMetadata>,
+  live_tasks: U64,
+  task_count: U64,
+  amount: U128,
+  amount_left: U128,
+  tge: Option<U64>
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(Debug, Clone)]
+pub struct Unlock {
+  pub account_id: AccountId,
+  pub amount: U128
+}
+
+/// Amounts that become claimable at `timestamp`.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(Debug, Clone)]
+pub struct SchedulePeriod {
+  pub timestamp: U64,
+  pub amount: U128,
+  pub unlocks: Vec<Unlock>
+}
+
+/// `remainder` is the part of the task amount left over by the equal split, it goes to
+/// the creator's treasury.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(Debug, Clone)]
+pub struct Schedule {
+  pub periods: Vec<SchedulePeriod>,
+  pub remainder: U128
+}
+
+fn token_info(token_id: AccountId, token: Token) -> TokenInfo {
+  TokenInfo {
+    token_id: token_id,
+    metadata: token.metadata,
+    live_tasks: token.live_tasks.into(),
+    task_count: token.tasks.len().into(),
+    amount: token.amount.into(),
+    amount_left: token.amount_left.into(),
+    tge: token.tge.map(|tge| tge.into())
+  }
+}
+
+fn task_info(index: u32, task: Task) -> TaskInfo {
+  TaskInfo {
+    creator_id: task.creator_id,
+    token_id: task.token_id,
+    start_time: task.start_time.into(),
+    end_time: task.end_time.into(),
+    vesting_period: task.vesting_period.into(),
+    amount: task.amount.into(),
+    status: task.status,
+    merkle_root: task.merkle_root.map(|merkle_root| merkle_root.into()),
+    acceptance_deadline: task.acceptance_deadline.map(|deadline| deadline.into()),
+    staking_pool: task.staking.map(|staking| staking.pool_id),
+    legs: task.legs.into_iter().map(|leg| LegInfo {
+      token_id: leg.token_id,
+      amount: leg.amount.into(),
+      amount_left: leg.amount_left.into()
+    }).collect(),
+    claim_deadline: task.claim_deadline.map(|deadline| deadline.into()),
+    awaiting_tge: task.awaiting_tge,
+    voting: task.voting,
+    index: index
+  }
+}
+
 impl Lockup {
-  pub(crate) fn internal_get_tasks(&self, sender: AccountId) -> Vec<Claim> {
-    let mut ret: Vec<Claim> = Vec::new();
-    for (index, task) in self.tasks.iter().enumerate() {
-      match task.accounts.get(&sender) {
-        Some(v) => {
-          ret.push(Claim {
-            should_claim: get_claim_amount(&task, &v),
-            amount_left: v.amount_left.into(),
-            claim_time: v.claim_time.into(),
-            index: index as u32
-          })
+  fn internal_get_task_page(&self, indexes: &Vector<u32>, from_index: u64, limit: u64) -> Vec<TaskInfo> {
+    (from_index..std::cmp::min(from_index.saturating_add(limit), indexes.len())).filter_map(|i| {
+      let index = indexes.get(i).unwrap();
+      match self.tasks.get(index as u64) {
+        Some(TaskEntry::Task(mut task)) => {
+          self.internal_resolve_start(&mut task);
+          Some(task_info(index, task))
         },
-        None => continue
+        _ => None
       }
-    }
-    ret
+    }).collect()
+  }
+
+  /// Positions of `account_id` from `from_index` of its index, as of `timestamp`,
+  /// assuming nothing more is claimed until then.
+  pub(crate) fn internal_get_positions(&self, account_id: AccountId, timestamp: u64, from_index: u64, limit: u64) -> Vec<Claim> {
+    let positions = match self.positions.get(&account_id) {
+      Some(positions) => positions,
+      None => return vec![]
+    };
+    let indexes = positions.as_vector();
+    (from_index..std::cmp::min(from_index.saturating_add(limit), indexes.len())).filter_map(|i| {
+      let index = indexes.get(i).unwrap();
+      let mut task = match self.tasks.get(index as u64) {
+        Some(TaskEntry::Task(task)) => task,
+        _ => return None
+      };
+      self.internal_resolve_start(&mut task);
+      let v = task.accounts.get(&account_id)?;
+      let next_unlock = get_next_unlock(&task, &v, timestamp);
+      let leg_amounts = get_leg_claim_amounts_at(&task, &v, timestamp);
+      Some(Claim {
+        should_claim: get_claim_amount_at(&task, &v, timestamp).into(),
+        amount_left: v.amount_left.into(),
+        claim_time: v.claim_time.into(),
+        index: index,
+        error: if v.transfer_failed {
+          Some("last transfer failed, register with the token or attach a deposit to claim".to_string())
+        } else {
+          None
+        },
+        next_unlock_time: next_unlock.map(|(time, _)| time.into()),
+        next_unlock_amount: next_unlock.map(|(_, amount)| amount.into()),
+        token_id: task.token_id.clone(),
+        start_time: task.start_time.into(),
+        end_time: task.end_time.into(),
+        vesting_period: task.vesting_period.into(),
+        amount: v.amount.into(),
+        accepted: v.accepted,
+        rewards: task.staking.as_ref().map_or(0, |staking| staking.pending_rewards(&v)).into(),
+        legs: task.legs.iter().zip(leg_amounts).map(|(leg, amount)| LegClaim {
+          token_id: leg.token_id.clone(),
+          should_claim: amount.into()
+        }).collect()
+      })
+    }).collect()
   }
 }
 
 #[near_bindgen]
 impl Lockup {
-  pub fn get_claim_info_by_creator(&self, account_id: AccountId) -> Vec<Claim> {
-    self.internal_get_tasks(account_id)
+  pub fn get_contract_info(&self) -> ContractInfo {
+    ContractInfo {
+      schema_version: SCHEMA_VERSION,
+      owner_id: self.owner_id.clone()
+    }
+  }
+
+  /// Positions of beneficiary `account_id`, `limit` of them from `from_index`.
+  pub fn get_positions(&self, account_id: AccountId, from_index: U64, limit: U64) -> Vec<Claim> {
+    self.internal_get_positions(account_id, env::block_timestamp(), from_index.into(), limit.into())
+  }
+
+  /// Same as `get_positions`, projected to `at_timestamp`, now by default.
+  pub fn get_positions_at(&self, account_id: AccountId, at_timestamp: Option<U64>, from_index: U64, limit: U64) -> Vec<Claim> {
+    let timestamp = at_timestamp.map_or(env::block_timestamp(), |timestamp| timestamp.into());
+    self.internal_get_positions(account_id, timestamp, from_index.into(), limit.into())
+  }
+
+  pub fn get_token_list(&self) -> Vec<TokenInfo> {
+    self.tokens.iter().map(|(token_id, token)| token_info(token_id, token)).collect()
+  }
+
+  /// Token `token_id` with the totals of its tasks.
+  pub fn get_token(&self, token_id: AccountId) -> Option<TokenInfo> {
+    self.tokens.get(&token_id).map(|token| token_info(token_id, token))
   }
 
   pub fn get_task(&self, index: u32) -> TaskInfo {
-    let task = self.tasks.get(index as u64).unwrap();
-    return TaskInfo {
-      token_id: task.token_id,
-      start_time: task.start_time,
-      end_time: task.end_time,
-      vesting_period: task.vesting_period,
-      amount: task.amount,
-      single_claim_amount: task.single_claim_amount,
-      index: index
+    task_info(index, self.internal_get_task(index))
+  }
+
+  /// Tasks of `token_id`, `limit` of them from `from_index`. Archived tasks are left out.
+  pub fn get_tasks_by_token_id(&self, token_id: AccountId, from_index: U64, limit: U64) -> Vec<TaskInfo> {
+    let token = self.tokens.get(&token_id).expect("token not exist");
+    self.internal_get_task_page(token.tasks.as_vector(), from_index.into(), limit.into())
+  }
+
+  /// Tasks created by `creator_id`, `limit` of them from `from_index`. Archived tasks are left out.
+  pub fn get_tasks_by_creator(&self, creator_id: AccountId, from_index: U64, limit: U64) -> Vec<TaskInfo> {
+    match self.creator_tasks.get(&creator_id) {
+      Some(tasks) => self.internal_get_task_page(tasks.as_vector(), from_index.into(), limit.into()),
+      None => vec![]
+    }
+  }
+
+  /// Amount of `token_id` held for `creator_id` out of any task.
+  pub fn get_treasury_balance(&self, creator_id: AccountId, token_id: AccountId) -> U128 {
+    self.treasuries.get(&(creator_id, token_id)).unwrap_or(0).into()
+  }
+
+  /// What `account_id` has left to claim of `token_id`, unless delegated, plus what
+  /// was delegated to it. Merkle allocations count from their first claim.
+  pub fn get_voting_power(&self, account_id: AccountId, token_id: AccountId) -> U128 {
+    self.get_voting_power_at(account_id, token_id, env::block_timestamp().into())
+  }
+
+  /// Voting power of `account_id` in `token_id` as of block `timestamp`.
+  pub fn get_voting_power_at(&self, account_id: AccountId, token_id: AccountId, timestamp: U64) -> U128 {
+    let timestamp: u64 = timestamp.into();
+    let checkpoints = self.internal_get_checkpoints(&account_id, &token_id);
+    // number of checkpoints at or before `timestamp`
+    let (mut low, mut high) = (0, checkpoints.len());
+    while low < high {
+      let mid = (low + high) / 2;
+      if checkpoints.get(mid).unwrap().timestamp <= timestamp {
+        low = mid + 1;
+      } else {
+        high = mid;
+      }
+    }
+    let power = match low {
+      0 => 0,
+      n => checkpoints.get(n - 1).unwrap().power
+    };
+    power.into()
+  }
+
+  /// Account the voting power of `account_id` in `token_id` counts for.
+  pub fn get_delegate(&self, account_id: AccountId, token_id: AccountId) -> AccountId {
+    self.internal_get_delegate(&account_id, &token_id)
+  }
+
+  pub fn preview_schedule(&self, task_args: TaskArgs) -> Schedule {
+    match internal::preview_schedule(&task_args) {
+      Ok(schedule) => schedule,
+      Err(err) => env::panic(err.to_string().as_bytes())
+    }
+  }
+
+  pub fn get_archived_task(&self, index: u32) -> Option<TaskSummary> {
+    match self.tasks.get(index as u64).expect("task not exist") {
+      TaskEntry::Archived(summary) => Some(summary),
+      TaskEntry::Task(_) => None
     }
   }
 }
\ No newline at end of file
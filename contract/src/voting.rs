@@ -0,0 +1,85 @@
+use crate::*;
+
+impl Lockup {
+    /// Account the voting power of `account_id` in `token_id` counts for, itself by default.
+    pub(crate) fn internal_get_delegate(&self, account_id: &AccountId, token_id: &AccountId) -> AccountId {
+        self.delegates.get(&(account_id.clone(), token_id.clone())).unwrap_or_else(|| account_id.clone())
+    }
+
+    pub(crate) fn internal_get_checkpoints(&self, account_id: &AccountId, token_id: &AccountId) -> Vector<Checkpoint> {
+        self.voting_checkpoints.get(&(account_id.clone(), token_id.clone())).unwrap_or_else(|| {
+            let key = format!("{}:{}", account_id, token_id);
+            Vector::new(StorageKey::AccountCheckpoints { key_hash: env::sha256(key.as_bytes()).try_into().unwrap() })
+        })
+    }
+
+    /// Adds `increase` and takes `decrease` off the voting power of `account_id` from
+    /// now on. Changes within a block share one checkpoint.
+    fn internal_update_voting_power(&mut self, account_id: &AccountId, token_id: &AccountId, increase: u128, decrease: u128) {
+        if increase == decrease {
+            return;
+        }
+        let mut checkpoints = self.internal_get_checkpoints(account_id, token_id);
+        let last = checkpoints.len().checked_sub(1).map(|i| (i, checkpoints.get(i).unwrap()));
+        let checkpoint = Checkpoint {
+            timestamp: env::block_timestamp(),
+            power: last.as_ref().map_or(0, |(_, checkpoint)| checkpoint.power) + increase - decrease,
+        };
+        match last {
+            Some((i, last)) if last.timestamp == checkpoint.timestamp => {
+                checkpoints.replace(i, &checkpoint);
+            },
+            _ => checkpoints.push(&checkpoint)
+        }
+        self.voting_checkpoints.insert(&(account_id.clone(), token_id.clone()), &checkpoints);
+    }
+
+    /// Records `amount` of `token_id` newly allocated to `account_id`.
+    pub(crate) fn internal_lock(&mut self, account_id: &AccountId, token_id: &AccountId, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+        let key = (account_id.clone(), token_id.clone());
+        self.locked_balances.insert(&key, &(self.locked_balances.get(&key).unwrap_or(0) + amount));
+        let delegate_id = self.internal_get_delegate(account_id, token_id);
+        self.internal_update_voting_power(&delegate_id, token_id, amount, 0);
+    }
+
+    /// Records `amount` of `token_id` claimed by or taken back from `account_id`.
+    pub(crate) fn internal_unlock(&mut self, account_id: &AccountId, token_id: &AccountId, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+        let key = (account_id.clone(), token_id.clone());
+        let balance = self.locked_balances.get(&key).unwrap_or(0) - amount;
+        if balance == 0 {
+            self.locked_balances.remove(&key);
+        } else {
+            self.locked_balances.insert(&key, &balance);
+        }
+        let delegate_id = self.internal_get_delegate(account_id, token_id);
+        self.internal_update_voting_power(&delegate_id, token_id, 0, amount);
+    }
+}
+
+#[near_bindgen]
+impl Lockup {
+    /// Moves the voting power of what the caller has locked of `token_id` to `delegate_id`,
+    /// or back to the caller when not given. Later allocations and claims follow it.
+    pub fn delegate(&mut self, token_id: AccountId, delegate_id: Option<AccountId>) {
+        let sender = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let current_id = self.internal_get_delegate(&sender, &token_id);
+        let delegate_id = delegate_id.unwrap_or_else(|| sender.clone());
+        assert!(current_id != delegate_id, "already delegated to {}", delegate_id);
+        let balance = self.locked_balances.get(&(sender.clone(), token_id.clone())).unwrap_or(0);
+        self.internal_update_voting_power(&current_id, &token_id, 0, balance);
+        self.internal_update_voting_power(&delegate_id, &token_id, balance, 0);
+        if delegate_id == sender {
+            self.delegates.remove(&(sender.clone(), token_id));
+        } else {
+            self.delegates.insert(&(sender.clone(), token_id), &delegate_id);
+        }
+        self.internal_update_storage(&sender, initial_storage);
+    }
+}
@@ -0,0 +1,182 @@
+/*!
+Factory of lockup contracts. It stores versions of the lockup code, deploys a lockup
+for a creator on a sub-account with `new(owner_id)`, keeps track of the lockups of
+each creator, and upgrades them to newer code.
+*/
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet};
+use near_sdk::json_types::{Base58CryptoHash, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{
+    env, ext_contract, log, near_bindgen, setup_alloc, AccountId, Balance, BorshStorageKey,
+    CryptoHash, Gas, PanicOnDefault, Promise, PromiseResult,
+};
+use std::convert::TryInto;
+
+setup_alloc!();
+
+const GAS_FOR_NEW: Gas = 20_000_000_000_000;
+const GAS_FOR_UPGRADE: Gas = 100_000_000_000_000;
+const GAS_FOR_ON_CREATE: Gas = 20_000_000_000_000;
+const GAS_FOR_ON_UPGRADE: Gas = 20_000_000_000_000;
+
+#[derive(BorshStorageKey, BorshSerialize)]
+pub(crate) enum StorageKey {
+    Codes,
+    Instances,
+    CreatorInstances,
+    CreatorInstanceIds { account_hash: CryptoHash },
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct Factory {
+    owner_id: AccountId,
+    /// Stored lockup code by its sha256.
+    codes: LookupMap<CryptoHash, Vec<u8>>,
+    /// Code deployed for new lockups.
+    code_hash: Option<CryptoHash>,
+    instances: UnorderedMap<AccountId, Instance>,
+    /// Lockups of each creator.
+    creator_instances: LookupMap<AccountId, UnorderedSet<AccountId>>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Instance {
+    creator_id: AccountId,
+    code_hash: CryptoHash,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(Debug, Clone)]
+pub struct InstanceInfo {
+    instance_id: AccountId,
+    creator_id: AccountId,
+    code_hash: Base58CryptoHash,
+}
+
+#[ext_contract(ext_self)]
+pub trait ExtSelf {
+    fn on_create(&mut self, instance_id: AccountId, creator_id: AccountId, code_hash: Base58CryptoHash, deposit: U128);
+
+    fn on_upgrade(&mut self, instance_id: AccountId, code_hash: Base58CryptoHash);
+}
+
+#[near_bindgen]
+impl Factory {
+    #[init]
+    pub fn new(owner_id: AccountId) -> Self {
+        Self {
+            owner_id,
+            codes: LookupMap::new(StorageKey::Codes),
+            code_hash: None,
+            instances: UnorderedMap::new(StorageKey::Instances),
+            creator_instances: LookupMap::new(StorageKey::CreatorInstances),
+        }
+    }
+
+    /// Stores the lockup code passed as raw input and deploys it for new lockups from
+    /// now on. The attached deposit pays its storage.
+    #[payable]
+    pub fn store_code(&mut self) -> Base58CryptoHash {
+        assert!(env::predecessor_account_id() == self.owner_id, "factory owner only");
+        let initial_storage = env::storage_usage();
+        let code = env::input().expect("code required");
+        let code_hash: CryptoHash = env::sha256(&code).try_into().unwrap();
+        self.codes.insert(&code_hash, &code);
+        self.code_hash = Some(code_hash);
+        let storage_cost = (env::storage_usage().saturating_sub(initial_storage)) as Balance * env::storage_byte_cost();
+        assert!(env::attached_deposit() >= storage_cost, "not enough deposit, {} required", storage_cost);
+        code_hash.into()
+    }
+
+    /// Deploys a lockup owned by the caller on `name.<factory>`. The attached deposit
+    /// is transferred to it to pay its storage, and refunded if the deployment fails.
+    #[payable]
+    pub fn create(&mut self, name: String) -> Promise {
+        let creator_id = env::predecessor_account_id();
+        let code_hash = self.code_hash.expect("no code stored");
+        let instance_id = format!("{}.{}", name, env::current_account_id());
+        assert!(env::is_valid_account_id(instance_id.as_bytes()), "invalid name");
+        assert!(self.instances.get(&instance_id).is_none(), "lockup already exists");
+        let deposit = env::attached_deposit();
+        Promise::new(instance_id.clone())
+            .create_account()
+            .transfer(deposit)
+            .deploy_contract(self.codes.get(&code_hash).unwrap())
+            .function_call(b"new".to_vec(), json!({ "owner_id": creator_id }).to_string().into_bytes(), 0, GAS_FOR_NEW)
+            .then(ext_self::on_create(instance_id, creator_id, code_hash.into(), deposit.into(), &env::current_account_id(), 0, GAS_FOR_ON_CREATE))
+    }
+
+    /// Upgrades a lockup of the caller to `code_hash`, the latest stored code by default.
+    /// The new code migrates the state it finds with its `migrate`.
+    pub fn upgrade(&mut self, instance_id: AccountId, code_hash: Option<Base58CryptoHash>) -> Promise {
+        let instance = self.instances.get(&instance_id).expect("lockup not exist");
+        assert!(env::predecessor_account_id() == instance.creator_id, "lockup creator only");
+        let code_hash: CryptoHash = code_hash.map(|code_hash| code_hash.into()).or(self.code_hash).expect("no code stored");
+        assert!(code_hash != instance.code_hash, "lockup already runs this code");
+        let code = self.codes.get(&code_hash).expect("code not exist");
+        Promise::new(instance_id.clone())
+            .function_call(b"upgrade".to_vec(), code, 0, GAS_FOR_UPGRADE)
+            .then(ext_self::on_upgrade(instance_id, code_hash.into(), &env::current_account_id(), 0, GAS_FOR_ON_UPGRADE))
+    }
+
+    #[private]
+    pub fn on_create(&mut self, instance_id: AccountId, creator_id: AccountId, code_hash: Base58CryptoHash, deposit: U128) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => {
+                self.instances.insert(&instance_id, &Instance {
+                    creator_id: creator_id.clone(),
+                    code_hash: code_hash.into(),
+                });
+                let mut instance_ids = self.creator_instances.get(&creator_id).unwrap_or_else(|| {
+                    UnorderedSet::new(StorageKey::CreatorInstanceIds { account_hash: env::sha256(creator_id.as_bytes()).try_into().unwrap() })
+                });
+                instance_ids.insert(&instance_id);
+                self.creator_instances.insert(&creator_id, &instance_ids);
+                true
+            },
+            _ => {
+                // the deposit came back to the factory with the failed deployment
+                log!("failed to create {}", instance_id);
+                Promise::new(creator_id).transfer(deposit.into());
+                false
+            }
+        }
+    }
+
+    #[private]
+    pub fn on_upgrade(&mut self, instance_id: AccountId, code_hash: Base58CryptoHash) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => {
+                let mut instance = self.instances.get(&instance_id).unwrap();
+                instance.code_hash = code_hash.into();
+                self.instances.insert(&instance_id, &instance);
+                true
+            },
+            _ => {
+                log!("failed to upgrade {}", instance_id);
+                false
+            }
+        }
+    }
+
+    pub fn get_code_hash(&self) -> Option<Base58CryptoHash> {
+        self.code_hash.map(|code_hash| code_hash.into())
+    }
+
+    pub fn get_instance(&self, instance_id: AccountId) -> Option<InstanceInfo> {
+        self.instances.get(&instance_id).map(|instance| InstanceInfo {
+            instance_id,
+            creator_id: instance.creator_id,
+            code_hash: instance.code_hash.into(),
+        })
+    }
+
+    /// Lockups created by `creator_id`.
+    pub fn get_instances(&self, creator_id: AccountId) -> Vec<AccountId> {
+        self.creator_instances.get(&creator_id).map_or(vec![], |instance_ids| instance_ids.to_vec())
+    }
+}
@@ -0,0 +1,40 @@
+//! Later version of the lockup, to test upgrades through the factory.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::serde::Serialize;
+use near_sdk::{env, near_bindgen, AccountId, PanicOnDefault};
+
+near_sdk::setup_alloc!();
+
+const SCHEMA_VERSION: u32 = 1000;
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct Contract {
+    owner_id: AccountId,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ContractInfo {
+    schema_version: u32,
+    owner_id: AccountId,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Reads the owner, the first field of the lockup state, and leaves the rest.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let state = env::storage_read(b"STATE").expect("failed to read old state");
+        let owner_id = AccountId::deserialize(&mut &state[..]).expect("failed to read owner");
+        Self { owner_id }
+    }
+
+    pub fn get_contract_info(&self) -> ContractInfo {
+        ContractInfo {
+            schema_version: SCHEMA_VERSION,
+            owner_id: self.owner_id.clone(),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Staking pool of a single fungible token, staked and withdrawn with `ft_transfer_call`.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde_json::{self, json};
+use near_sdk::{env, ext_contract, near_bindgen, AccountId, Gas, PanicOnDefault, PromiseOrValue, PromiseResult};
+
+near_sdk::setup_alloc!();
+
+const GAS_FOR_FT_TRANSFER_CALL: Gas = 50_000_000_000_000;
+const GAS_FOR_ON_WITHDRAWN: Gas = 10_000_000_000_000;
+
+#[ext_contract(ext_fungible_token)]
+pub trait FungibleTokenContract {
+    fn ft_transfer_call(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>, msg: String) -> U128;
+}
+
+#[ext_contract(ext_self)]
+pub trait ExtSelf {
+    fn on_withdrawn(&mut self, stake_id: String, amount: U128, reward: U128) -> U128;
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct Contract {
+    token_id: AccountId,
+    /// By `<account_id>:<key>`.
+    deposits: LookupMap<String, u128>,
+    rewards: LookupMap<String, u128>,
+    /// Added to what `withdraw` reports, like a pool lying about the rewards it sent.
+    report_extra: u128,
+    /// Makes `withdraw` resolve before the tokens it sends arrive.
+    detach_returns: bool,
+}
+
+fn stake_id(account_id: &str, key: &str) -> String {
+    format!("{}:{}", account_id, key)
+}
+
+#[near_bindgen]
+impl Contract {
+    #[init]
+    pub fn new(token_id: ValidAccountId) -> Self {
+        Self {
+            token_id: token_id.into(),
+            deposits: LookupMap::new(b"d".to_vec()),
+            rewards: LookupMap::new(b"r".to_vec()),
+            report_extra: 0,
+            detach_returns: false,
+        }
+    }
+
+    pub fn ft_on_transfer(&mut self, sender_id: ValidAccountId, amount: U128, msg: String) -> U128 {
+        assert_eq!(env::predecessor_account_id(), self.token_id, "unknown token");
+        let amount: u128 = amount.into();
+        match msg.strip_prefix("reward:") {
+            Some(stake_id) => {
+                self.rewards.insert(&stake_id.to_string(), &(self.rewards.get(&stake_id.to_string()).unwrap_or(0) + amount));
+            }
+            None => {
+                let stake_id = stake_id(sender_id.as_ref(), &msg);
+                self.deposits.insert(&stake_id, &(self.deposits.get(&stake_id).unwrap_or(0) + amount));
+            }
+        }
+        U128(0)
+    }
+
+    /// Transfers `amount` of the stake `key` of the caller back, with all its rewards.
+    /// Resolves to the amount reported as transferred, 0 if the transfer failed.
+    pub fn withdraw(&mut self, key: String, amount: U128) -> PromiseOrValue<U128> {
+        let account_id = env::predecessor_account_id();
+        let stake_id = stake_id(&account_id, &key);
+        let amount: u128 = amount.into();
+        let deposit = self.deposits.get(&stake_id).unwrap_or(0);
+        assert!(amount <= deposit, "not enough deposit");
+        let reward = self.rewards.remove(&stake_id).unwrap_or(0);
+        if amount + reward == 0 {
+            return PromiseOrValue::Value(U128(self.report_extra));
+        }
+        self.deposits.insert(&stake_id, &(deposit - amount));
+        let msg = json!({ "stake_key": key }).to_string();
+        if self.detach_returns {
+            ext_fungible_token::ft_transfer_call(account_id, U128(amount + reward), None, msg, &self.token_id, 1, GAS_FOR_FT_TRANSFER_CALL);
+            return PromiseOrValue::Value(U128(amount + reward + self.report_extra));
+        }
+        ext_fungible_token::ft_transfer_call(account_id, U128(amount + reward), None, msg, &self.token_id, 1, GAS_FOR_FT_TRANSFER_CALL).then(
+            ext_self::on_withdrawn(stake_id, U128(amount), U128(reward), &env::current_account_id(), 0, GAS_FOR_ON_WITHDRAWN)
+        ).into()
+    }
+
+    #[private]
+    pub fn on_withdrawn(&mut self, stake_id: String, amount: U128, reward: U128) -> U128 {
+        let used: u128 = match env::promise_result(0) {
+            PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value).map_or(0, |used| used.into()),
+            _ => 0,
+        };
+        if used < amount.0 + reward.0 {
+            // the depositor refused the tokens, the stake is kept
+            self.deposits.insert(&stake_id, &(self.deposits.get(&stake_id).unwrap_or(0) + amount.0));
+            self.rewards.insert(&stake_id, &(self.rewards.get(&stake_id).unwrap_or(0) + reward.0));
+            return U128(0);
+        }
+        U128(used + self.report_extra)
+    }
+
+    /// Makes `withdraw` report `amount` more than it transfers.
+    pub fn set_report_extra(&mut self, amount: U128) {
+        self.report_extra = amount.into();
+    }
+
+    /// Makes `withdraw` resolve without waiting for the tokens it sends.
+    pub fn set_detach_returns(&mut self, detach: bool) {
+        self.detach_returns = detach;
+    }
+
+    pub fn get_deposit(&self, account_id: AccountId, key: String) -> U128 {
+        U128(self.deposits.get(&stake_id(&account_id, &key)).unwrap_or(0))
+    }
+
+    pub fn get_reward(&self, account_id: AccountId, key: String) -> U128 {
+        U128(self.rewards.get(&stake_id(&account_id, &key)).unwrap_or(0))
+    }
+}
@@ -20,9 +20,9 @@ export async function initContract() {
   // Initializing our contract APIs by contract name and configuration
   window.contract = await new Contract(window.walletConnection.account(), nearConfig.contractName, {
     // View methods are read only. They don't modify the state, but usually return some value.
-    viewMethods: ['get_token_list', 'get_tasks', 'get_tasks_by_token_id'],
+    viewMethods: ['get_token_list', 'get_token', 'get_positions', 'get_tasks_by_token_id'],
     // Change methods can modify the state. But you don't receive the returned value when called.
-    changeMethods: ['add_token', 'add_task', 'claim'],
+    changeMethods: ['add_token', 'claim'],
   })
 }
 
@@ -46,6 +46,19 @@ export async function getTokenBalance(token_id) {
   return contract.ft_balance_of({account_id: nearConfig.contractName})
 }
 
+// Tasks are created by transferring the tokens to the lockup with the task arguments as msg
+export async function fundTask(token_id, amount, task_args) {
+  let contract = await new Contract(window.walletConnection.account(), token_id, {
+    viewMethods: [],
+    changeMethods: ['ft_transfer_call'],
+  })
+  return contract.ft_transfer_call({
+    receiver_id: nearConfig.contractName,
+    amount: amount,
+    msg: JSON.stringify(task_args)
+  }, "300000000000000", "1")
+}
+
 export function logout() {
   window.walletConnection.signOut()
   // reload page
@@ -1,84 +1,1339 @@
-pub mod utils;
-use std::convert::TryInto;
-
-use near_sdk::borsh::BorshSerialize;
-use near_sdk::serde_json::json;
-use near_sdk::{PromiseOrValue};
-use near_sdk::json_types::U128;
-use near_sdk_sim::account::{AccessKey, AccessKeyPermission, FunctionCallPermission};
-use near_sdk_sim::near_crypto::{InMemorySigner, PublicKey};
-use near_sdk_sim::to_yocto;
-use near_sdk_sim::{call, view, deploy, init_simulator, ContractAccount, UserAccount, DEFAULT_GAS};
-
-#[test]
-fn simulate_add_task() {
-    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
-
-    //let runtime = root.borrow_runtime_mut();
-
-    let transfer_amount = to_yocto("100");
-    call!(
-        root,
-        ft.ft_transfer(alice.valid_account_id(), transfer_amount.into(),  None),
-        deposit = 1
-    )
-    .assert_success();
-
-    //runtime.produce_block().unwrap();
-
-    let result: U128 = view!(
-        ft.ft_balance_of(alice.valid_account_id())
-    )
-    .unwrap_json();
-    println!("{:?}", result);
-
-    //runtime.produce_block().unwrap();
-
-    call!(
-        alice,
-        lockup.add_token(ft.account_id()),
-        near_sdk::env::storage_byte_cost() * 125,
-        DEFAULT_GAS
-    ).assert_success();
-    
-    //runtime.produce_block().unwrap();
-
-    call!(
-        alice,
-        ft.ft_transfer_call(lockup.valid_account_id(), transfer_amount.into(), Option::None, "".to_string()),
-        1,
-        DEFAULT_GAS
-    ).assert_success();
-
-    let result: U128 = view!(
-        ft.ft_balance_of(lockup.valid_account_id())
-    )
-    .unwrap_json();
-    println!("{:?}", result);
-
-    let bob = root.create_user("bob".to_string(), to_yocto("10000"));
-    let john = root.create_user("john".to_string(), to_yocto("10000"));
-
-    //runtime.produce_block().unwrap();
-
-    call!(
-        alice,
-        lockup.add_task(ft.account_id(), vec!["bob".to_string(), "john".to_string()], 0.into(), 1050.into(), 100.into(), transfer_amount.into())
-    )
-    .assert_success();
-
-    //runtime.produce_block().unwrap();
-    
-    // call!(
-    //     bob,
-    //     lockup.claim(ft.account_id(), 0)
-    // )
-    // .assert_success();
-
-    // let result: U128 = view!(
-    //     ft.ft_balance_of(bob.valid_account_id())
-    // )
-    // .unwrap_json();
-    // println!("{:?}", result);
-}
-
+pub mod utils;
+use std::convert::TryInto;
+
+use near_sdk::borsh::BorshSerialize;
+use near_sdk::serde_json::{json, Value};
+use near_sdk::{PromiseOrValue};
+use near_sdk::json_types::{Base58CryptoHash, U128, U64};
+use near_sdk_sim::account::{AccessKey, AccessKeyPermission, FunctionCallPermission};
+use near_sdk_sim::near_crypto::{InMemorySigner, PublicKey};
+use near_sdk_sim::to_yocto;
+use near_sdk_sim::{call, view, deploy, init_simulator, ContractAccount, UserAccount, DEFAULT_GAS};
+use lockup::view::TokenInfo;
+
+#[test]
+fn simulate_add_task() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+
+    //let runtime = root.borrow_runtime_mut();
+
+    let transfer_amount = to_yocto("100");
+    call!(
+        root,
+        ft.ft_transfer(alice.valid_account_id(), transfer_amount.into(),  None),
+        deposit = 1
+    )
+    .assert_success();
+
+    //runtime.produce_block().unwrap();
+
+    let result: U128 = view!(
+        ft.ft_balance_of(alice.valid_account_id())
+    )
+    .unwrap_json();
+    println!("{:?}", result);
+
+    //runtime.produce_block().unwrap();
+
+    utils::register_creator(&lockup, &alice);
+    call!(
+        alice,
+        lockup.add_token(ft.account_id()),
+        to_yocto("1"),
+        DEFAULT_GAS
+    ).assert_success();
+    
+    //runtime.produce_block().unwrap();
+
+    let bob = root.create_user("bob".to_string(), to_yocto("10000"));
+    let john = root.create_user("john".to_string(), to_yocto("10000"));
+
+    utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "token_id": ft.account_id(),
+            "account_list": [bob.account_id(), john.account_id()],
+            "start_time": "0",
+            "end_time": "1050",
+            "vesting_period": "100",
+            "amount": transfer_amount.to_string()
+        }),
+        transfer_amount
+    ).assert_success();
+
+    let result: U128 = view!(
+        ft.ft_balance_of(lockup.valid_account_id())
+    )
+    .unwrap_json();
+    println!("{:?}", result);
+
+    //runtime.produce_block().unwrap();
+    
+    // call!(
+    //     bob,
+    //     lockup.claim(ft.account_id(), 0)
+    // )
+    // .assert_success();
+
+    // let result: U128 = view!(
+    //     ft.ft_balance_of(bob.valid_account_id())
+    // )
+    // .unwrap_json();
+    // println!("{:?}", result);
+}
+
+#[test]
+fn simulate_add_token_refund() {
+    let (_root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::register_creator(&lockup, &alice);
+
+    let balance_before = alice.account().unwrap().amount;
+    call!(
+        alice,
+        lockup.add_token(ft.account_id()),
+        to_yocto("1"),
+        DEFAULT_GAS
+    ).assert_success();
+
+    let tokens: Vec<TokenInfo> = view!(lockup.get_token_list()).unwrap_json();
+    assert_eq!(tokens.len(), 1);
+    // only the storage deposit is kept, the rest of the attached NEAR comes back
+    let balance_after = alice.account().unwrap().amount;
+    assert!(balance_before - balance_after < to_yocto("0.1"));
+}
+
+#[test]
+fn simulate_add_token_rejected() {
+    let (root, lockup, _ft, alice) = utils::init(to_yocto("100000"));
+    let reject_ft = utils::init_reject_ft(&root);
+    utils::register_creator(&lockup, &alice);
+
+    let balance_before = alice.account().unwrap().amount;
+    call!(
+        alice,
+        lockup.add_token(reject_ft.account_id()),
+        to_yocto("1"),
+        DEFAULT_GAS
+    ).assert_success();
+
+    let tokens: Vec<TokenInfo> = view!(lockup.get_token_list()).unwrap_json();
+    assert!(tokens.is_empty());
+    let balance_after = alice.account().unwrap().amount;
+    assert!(balance_before - balance_after < to_yocto("0.1"));
+}
+
+#[test]
+fn simulate_add_token_twice() {
+    let (_root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::register_creator(&lockup, &alice);
+
+    // both calls find the token missing before either registers it
+    let balance_before = alice.account().unwrap().amount;
+    let args = json!({ "token_id": ft.account_id() }).to_string().into_bytes();
+    alice
+        .create_transaction(lockup.account_id())
+        .function_call("add_token".to_string(), args.clone(), DEFAULT_GAS / 2, to_yocto("1"))
+        .function_call("add_token".to_string(), args, DEFAULT_GAS / 2, to_yocto("1"))
+        .submit()
+        .assert_success();
+
+    let tokens: Vec<TokenInfo> = view!(lockup.get_token_list()).unwrap_json();
+    assert_eq!(tokens.len(), 1);
+    let balance_after = alice.account().unwrap().amount;
+    assert!(balance_before - balance_after < to_yocto("0.1"));
+}
+
+#[test]
+fn simulate_remove_token() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, 1000);
+    let bob = root.create_user("bob".to_string(), to_yocto("100"));
+    utils::register_user("ft", &bob);
+
+    utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "token_id": ft.account_id(),
+            "account_list": [bob.account_id()],
+            "start_time": "0",
+            "end_time": "1000",
+            "vesting_period": "100",
+            "amount": "1000"
+        }),
+        1000
+    ).assert_success();
+    assert!(!call!(alice, lockup.remove_token(ft.account_id())).is_ok());
+
+    call!(bob, lockup.claim(ft.account_id(), 0, None, None), utils::CLAIM_DEPOSIT, DEFAULT_GAS).assert_success();
+    let token: Value = view!(lockup.get_token(ft.account_id())).unwrap_json_value();
+    assert_eq!(token["live_tasks"], "0");
+    assert!(!call!(bob, lockup.remove_token(ft.account_id())).is_ok());
+    call!(alice, lockup.remove_token(ft.account_id())).assert_success();
+    let tokens: Vec<TokenInfo> = view!(lockup.get_token_list()).unwrap_json();
+    assert!(tokens.is_empty());
+}
+
+#[test]
+fn simulate_task_storage_keys_do_not_collide() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    let ft1 = utils::deploy_ft(&root, "ft1", to_yocto("100000"));
+    utils::register_user("ft1", &alice);
+    utils::setup_token(&root, &lockup, &ft, &alice, to_yocto("1000"));
+    utils::setup_token(&root, &lockup, &ft1, &alice, to_yocto("1000"));
+    let bob = root.create_user("bob".to_string(), to_yocto("100"));
+
+    // task 0 of "ft1" and task 10 of "ft" both used the "ft10" prefix
+    let task_args = |token_id: String, amount: u128| json!({
+        "token_id": token_id,
+        "account_list": [bob.account_id()],
+        "start_time": "0",
+        "end_time": "1000",
+        "vesting_period": "100",
+        "amount": amount.to_string()
+    });
+    utils::create_task(&alice, &lockup, &ft1, task_args(ft1.account_id(), 1000), 1000).assert_success();
+    for _ in 0..10 {
+        utils::create_task(&alice, &lockup, &ft, task_args(ft.account_id(), 100), 100).assert_success();
+    }
+
+    let claims = view!(lockup.get_positions(bob.account_id(), 0.into(), 100.into())).unwrap_json_value();
+    let claims = claims.as_array().unwrap();
+    assert_eq!(claims.len(), 11);
+    assert_eq!(claims[0]["amount_left"], "1000");
+    assert_eq!(claims[10]["amount_left"], "100");
+
+    let page = view!(lockup.get_positions(bob.account_id(), 10.into(), 5.into())).unwrap_json_value();
+    assert_eq!(page.as_array().unwrap().len(), 1);
+    assert_eq!(page[0]["token_id"], ft.account_id());
+}
+
+#[test]
+fn simulate_storage_charged_to_creator() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    // the token is registered by another creator, alice starts without a storage deposit
+    utils::register_creator(&lockup, &root);
+    call!(root, lockup.add_token(ft.account_id()), to_yocto("1"), DEFAULT_GAS).assert_success();
+    call!(root, ft.ft_transfer(alice.valid_account_id(), 1000.into(), None), deposit = 1).assert_success();
+    let bob = root.create_user("bob".to_string(), to_yocto("100"));
+    utils::register_user("ft", &bob);
+    let task_args = json!({
+        "token_id": ft.account_id(),
+        "account_list": [bob.account_id()],
+        "start_time": "0",
+        "end_time": "1000",
+        "vesting_period": "100",
+        "amount": "1000"
+    });
+
+    // the minimum only covers the account record itself
+    let bounds: Value = view!(lockup.storage_balance_bounds()).unwrap_json_value();
+    let min_balance: u128 = bounds["min"].as_str().unwrap().parse().unwrap();
+    call!(
+        alice,
+        lockup.storage_deposit(None, None),
+        min_balance,
+        DEFAULT_GAS
+    ).assert_success();
+    utils::create_task(&alice, &lockup, &ft, task_args.clone(), 1000);
+    let balance: U128 = view!(ft.ft_balance_of(alice.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 1000);
+    let tasks: Value = view!(lockup.get_tasks_by_creator(alice.account_id(), 0.into(), 10.into())).unwrap_json_value();
+    assert!(tasks.as_array().unwrap().is_empty());
+
+    utils::register_creator(&lockup, &alice);
+    utils::create_task(&alice, &lockup, &ft, task_args, 1000).assert_success();
+    let storage: Value = view!(lockup.storage_balance_of(alice.valid_account_id())).unwrap_json_value();
+    let available_before: u128 = storage["available"].as_str().unwrap().parse().unwrap();
+    assert!(available_before < to_yocto("1"));
+
+    // the position of bob is removed once fully claimed, its storage is credited back
+    call!(bob, lockup.claim(ft.account_id(), 0, None, None), utils::CLAIM_DEPOSIT, DEFAULT_GAS).assert_success();
+    let storage: Value = view!(lockup.storage_balance_of(alice.valid_account_id())).unwrap_json_value();
+    let available_after: u128 = storage["available"].as_str().unwrap().parse().unwrap();
+    assert!(available_after > available_before);
+}
+
+#[test]
+fn simulate_register_accounts() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, 2000);
+    let bob = root.create_user("bob".to_string(), to_yocto("100"));
+    utils::register_user("ft", &bob);
+    let storage_balance_of = |account_id: String| {
+        root.view(ft.account_id(), "storage_balance_of", &json!({ "account_id": account_id }).to_string().into_bytes()).unwrap_json_value()
+    };
+    let storage_total = || {
+        let storage: Value = view!(lockup.storage_balance_of(alice.valid_account_id())).unwrap_json_value();
+        storage["total"].as_str().unwrap().parse::<u128>().unwrap()
+    };
+    let bounds: Value = root.view(ft.account_id(), "storage_balance_bounds", b"{}").unwrap_json_value();
+    let storage_balance_min: u128 = bounds["min"].as_str().unwrap().parse().unwrap();
+
+    // only carol has to be registered, and paid for
+    let total_before = storage_total();
+    utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "token_id": ft.account_id(),
+            "account_list": [bob.account_id(), "carol"],
+            "start_time": "0",
+            "end_time": "1000",
+            "vesting_period": "100",
+            "amount": "1000",
+            "register_accounts": true
+        }),
+        1000
+    ).assert_success();
+    assert!(!storage_balance_of("carol".to_string()).is_null());
+    assert_eq!(total_before - storage_total(), storage_balance_min);
+
+    // more registrations than the transfer has gas for
+    let result = utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "token_id": ft.account_id(),
+            "account_list": ["a1", "a2", "a3", "a4", "a5"],
+            "start_time": "0",
+            "end_time": "1000",
+            "vesting_period": "100",
+            "amount": "1000",
+            "register_accounts": true
+        }),
+        1000
+    );
+    utils::assert_logged(&result, "E025");
+    assert!(storage_balance_of("a1".to_string()).is_null());
+    let balance: U128 = view!(ft.ft_balance_of(alice.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 1000);
+
+    // larger lists are registered in batches once the task is created
+    let account_ids: Vec<String> = (1..=5).map(|i| format!("a{}", i)).collect();
+    utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "token_id": ft.account_id(),
+            "account_list": account_ids,
+            "start_time": "0",
+            "end_time": "1000",
+            "vesting_period": "100",
+            "amount": "1000"
+        }),
+        1000
+    ).assert_success();
+    assert!(!call!(alice, lockup.register_beneficiaries(1, vec!["a1".to_string(), "dave".to_string()]), 0, DEFAULT_GAS).is_ok());
+    let total_before = storage_total();
+    call!(alice, lockup.register_beneficiaries(1, account_ids.clone()), 0, DEFAULT_GAS).assert_success();
+    for account_id in account_ids {
+        assert!(!storage_balance_of(account_id).is_null());
+    }
+    assert_eq!(total_before - storage_total(), 5 * storage_balance_min);
+}
+
+#[test]
+fn simulate_archive_task() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, 2000);
+    let bob = root.create_user("bob".to_string(), to_yocto("100"));
+    utils::register_user("ft", &bob);
+
+    utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "token_id": ft.account_id(),
+            "account_list": [bob.account_id()],
+            "start_time": "0",
+            "end_time": "1000",
+            "vesting_period": "100",
+            "amount": "1000"
+        }),
+        1000
+    ).assert_success();
+    // a single leaf is its own root, with an empty proof
+    utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "token_id": ft.account_id(),
+            "account_list": [],
+            "start_time": "0",
+            "end_time": "1000",
+            "vesting_period": "100",
+            "amount": "1000",
+            "merkle_root": Base58CryptoHash::from(utils::merkle_leaf(&bob.account_id(), 1000))
+        }),
+        1000
+    ).assert_success();
+    assert!(!call!(alice, lockup.archive_task(0)).is_ok());
+
+    // the fully claimed entry is removed along with its position
+    call!(bob, lockup.claim(ft.account_id(), 0, None, None), utils::CLAIM_DEPOSIT, DEFAULT_GAS).assert_success();
+    let task: Value = view!(lockup.get_task(0)).unwrap_json_value();
+    assert_eq!(task["status"], "Completed");
+    let positions: Value = view!(lockup.get_positions(bob.account_id(), 0.into(), 10.into())).unwrap_json_value();
+    assert!(positions.as_array().unwrap().is_empty());
+
+    assert!(!call!(bob, lockup.archive_task(0)).is_ok());
+    call!(alice, lockup.archive_task(0)).assert_success();
+    let summary: Value = view!(lockup.get_archived_task(0)).unwrap_json_value();
+    assert_eq!(summary["token_id"], ft.account_id());
+    assert_eq!(summary["amount"], "1000");
+    assert!(view!(lockup.get_task(0)).is_err());
+    assert!(!call!(alice, lockup.archive_task(0)).is_ok());
+    // archived tasks are taken out of the lists, pages stay full
+    let tasks: Value = view!(lockup.get_tasks_by_creator(alice.account_id(), 0.into(), 1.into())).unwrap_json_value();
+    assert_eq!(tasks[0]["index"], 1);
+    let token: Value = view!(lockup.get_token(ft.account_id())).unwrap_json_value();
+    assert_eq!(token["task_count"], "1");
+
+    // a paid out merkle allocation is kept until cleared
+    call!(bob, lockup.claim(ft.account_id(), 1, Some(1000.into()), Some(vec![])), utils::CLAIM_DEPOSIT, DEFAULT_GAS).assert_success();
+    let balance: U128 = view!(ft.ft_balance_of(bob.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 2000);
+    let positions: Value = view!(lockup.get_positions(bob.account_id(), 0.into(), 10.into())).unwrap_json_value();
+    assert_eq!(positions[0]["index"], 1);
+    assert_eq!(positions[0]["amount_left"], "0");
+    assert!(!call!(alice, lockup.archive_task(1)).is_ok());
+    assert!(!call!(bob, lockup.clear_accounts(1, vec![bob.account_id()])).is_ok());
+    call!(alice, lockup.clear_accounts(1, vec![bob.account_id()])).assert_success();
+    let positions: Value = view!(lockup.get_positions(bob.account_id(), 0.into(), 10.into())).unwrap_json_value();
+    assert!(positions.as_array().unwrap().is_empty());
+    // the proof can't be used again once cleared
+    assert!(!call!(bob, lockup.claim(ft.account_id(), 1, Some(1000.into()), Some(vec![])), utils::CLAIM_DEPOSIT, DEFAULT_GAS).is_ok());
+    call!(alice, lockup.archive_task(1)).assert_success();
+}
+
+#[test]
+fn simulate_invalid_task_refunded() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, to_yocto("100"));
+
+    let result = utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "token_id": ft.account_id(),
+            "account_list": ["bob"],
+            "start_time": "0",
+            "end_time": "1000",
+            "vesting_period": "0",
+            "amount": to_yocto("100").to_string()
+        }),
+        to_yocto("100")
+    );
+    result.assert_success();
+    utils::assert_logged(&result, "E008");
+
+    let balance: U128 = view!(ft.ft_balance_of(alice.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, to_yocto("100"));
+}
+
+#[test]
+fn simulate_merge_and_add_beneficiaries() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, to_yocto("1000"));
+
+    utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "token_id": ft.account_id(),
+            "account_list": ["bob", "john", "bob"],
+            "start_time": "0",
+            "end_time": "1000",
+            "vesting_period": "100",
+            "amount": "3000",
+            "merge_duplicates": true
+        }),
+        3000
+    ).assert_success();
+    utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "index": 0,
+            "account_list": ["carol"],
+            "amount": "500"
+        }),
+        500
+    ).assert_success();
+
+    let bob: Value = view!(lockup.get_positions("bob".to_string(), 0.into(), 10.into())).unwrap_json_value();
+    assert_eq!(bob[0]["amount_left"], "2000");
+    let carol: Value = view!(lockup.get_positions("carol".to_string(), 0.into(), 10.into())).unwrap_json_value();
+    assert_eq!(carol[0]["amount_left"], "500");
+    let balance: U128 = view!(ft.ft_balance_of(lockup.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 3500);
+
+    let token: Value = view!(lockup.get_token(ft.account_id())).unwrap_json_value();
+    assert_eq!(token["task_count"], "1");
+    assert_eq!(token["amount"], "3500");
+    assert_eq!(token["amount_left"], "3500");
+    let tasks: Value = view!(lockup.get_tasks_by_token_id(ft.account_id(), 0.into(), 10.into())).unwrap_json_value();
+    assert_eq!(tasks.as_array().unwrap().len(), 1);
+    assert_eq!(tasks[0]["index"], 0);
+}
+
+#[test]
+fn simulate_preview_schedule() {
+    let (_root, lockup, ft, _alice) = utils::init(to_yocto("100000"));
+
+    let task_args: Value = json!({
+        "token_id": ft.account_id(),
+        "account_list": ["bob", "john"],
+        "start_time": "0",
+        "end_time": "300",
+        "vesting_period": "100",
+        "amount": "101"
+    });
+    let schedule: Value = view!(lockup.preview_schedule(near_sdk::serde_json::from_value(task_args).unwrap())).unwrap_json_value();
+    assert_eq!(schedule["remainder"], "1");
+    let periods = schedule["periods"].as_array().unwrap();
+    assert_eq!(periods.len(), 3);
+    let unlocked: Vec<&str> = periods.iter().map(|period| period["unlocks"][0]["amount"].as_str().unwrap()).collect();
+    assert_eq!(unlocked, vec!["16", "17", "17"]);
+    assert_eq!(periods[0]["timestamp"], "100");
+    assert_eq!(periods[0]["amount"], "32");
+    assert_eq!(periods[2]["unlocks"][1]["account_id"], "john");
+
+    // vesting doesn't overflow for amounts close to u128::MAX
+    let task_args: Value = json!({
+        "token_id": ft.account_id(),
+        "account_list": ["bob"],
+        "start_time": "0",
+        "end_time": "300",
+        "vesting_period": "100",
+        "amount": u128::MAX.to_string()
+    });
+    let schedule: Value = view!(lockup.preview_schedule(near_sdk::serde_json::from_value(task_args).unwrap())).unwrap_json_value();
+    let third = (u128::MAX / 3).to_string();
+    let unlocked: Vec<&str> = schedule["periods"].as_array().unwrap().iter().map(|period| period["amount"].as_str().unwrap()).collect();
+    assert_eq!(unlocked, vec![third.as_str(); 3]);
+}
+
+#[test]
+fn simulate_claim_info_at() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, to_yocto("1000"));
+    utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "token_id": ft.account_id(),
+            "account_list": ["bob"],
+            "start_time": "0",
+            "end_time": "1000",
+            "vesting_period": "100",
+            "amount": "1000"
+        }),
+        1000
+    ).assert_success();
+
+    let claims: Value = view!(lockup.get_positions_at("bob".to_string(), Some(250.into()), 0.into(), 10.into())).unwrap_json_value();
+    assert_eq!(claims[0]["should_claim"], "200");
+    assert_eq!(claims[0]["next_unlock_time"], "300");
+    assert_eq!(claims[0]["next_unlock_amount"], "100");
+    let claims: Value = view!(lockup.get_positions_at("bob".to_string(), Some(1000.into()), 0.into(), 10.into())).unwrap_json_value();
+    assert_eq!(claims[0]["should_claim"], "1000");
+    assert_eq!(claims[0]["next_unlock_time"], Value::Null);
+}
+
+#[test]
+fn simulate_top_up_keeps_vested() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, 1500);
+    let period: u64 = 100_000_000_000;
+    let start = utils::now(&root);
+    utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "token_id": ft.account_id(),
+            "account_list": ["bob"],
+            "start_time": start.to_string(),
+            "end_time": (start + 10 * period).to_string(),
+            "vesting_period": period.to_string(),
+            "amount": "1000"
+        }),
+        1000
+    ).assert_success();
+    let should_claim_at = |timestamp: u64| {
+        let claims: Value = view!(lockup.get_positions_at("bob".to_string(), Some(timestamp.into()), 0.into(), 10.into())).unwrap_json_value();
+        claims[0]["should_claim"].as_str().unwrap().to_string()
+    };
+
+    // halfway through the third period, two tenths are vested
+    utils::wait_until(&root, start + 5 * period / 2);
+    utils::create_task(&alice, &lockup, &ft, json!({ "index": 0, "account_id": "bob", "amount": "500" }), 500).assert_success();
+    assert_eq!(should_claim_at(utils::now(&root)), "200");
+    // the other 1300 vest over the 8 periods left
+    assert_eq!(should_claim_at(start + 3 * period), "362");
+    assert_eq!(should_claim_at(start + 10 * period), "1500");
+}
+
+#[test]
+fn simulate_extend_keeps_vested() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, 1180);
+    let period: u64 = 100_000_000_000;
+    let start = utils::now(&root);
+    utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "token_id": ft.account_id(),
+            "account_list": ["bob"],
+            "start_time": start.to_string(),
+            "end_time": (start + 10 * period).to_string(),
+            "vesting_period": period.to_string(),
+            "amount": "1000"
+        }),
+        1000
+    ).assert_success();
+    let should_claim_at = |timestamp: u64| {
+        let claims: Value = view!(lockup.get_positions_at("bob".to_string(), Some(timestamp.into()), 0.into(), 10.into())).unwrap_json_value();
+        claims[0]["should_claim"].as_str().unwrap().to_string()
+    };
+
+    utils::wait_until(&root, start + 5 * period / 2);
+    assert!(!call!(root, lockup.extend_task(0, (start + 20 * period).into())).is_ok());
+    assert!(!call!(alice, lockup.extend_task(0, (start + 5 * period).into())).is_ok());
+    call!(alice, lockup.extend_task(0, (start + 20 * period).into())).assert_success();
+    assert_eq!(should_claim_at(utils::now(&root)), "200");
+    // the other 800 vest over the 18 periods left
+    assert_eq!(should_claim_at(start + 3 * period), "244");
+
+    // a top-up after the extension keeps what the extended schedule vested
+    utils::wait_until(&root, start + 9 * period / 2);
+    utils::create_task(&alice, &lockup, &ft, json!({ "index": 0, "account_id": "bob", "amount": "180" }), 180).assert_success();
+    assert_eq!(should_claim_at(utils::now(&root)), "288");
+    // the other 892 vest over the 16 periods left
+    assert_eq!(should_claim_at(start + 5 * period), "343");
+    assert_eq!(should_claim_at(start + 20 * period), "1180");
+}
+
+#[test]
+fn simulate_draft_task() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, 2000);
+    let bob = root.create_user("bob".to_string(), to_yocto("100"));
+    utils::register_user("ft", &bob);
+
+    let draft_args = json!({
+        "token_id": ft.account_id(),
+        "account_list": [],
+        "start_time": "0",
+        "end_time": "1000",
+        "vesting_period": "100",
+        "amount": "1000",
+        "draft": true
+    });
+    utils::create_task(&alice, &lockup, &ft, draft_args.clone(), 1000).assert_success();
+    let append_args = json!({
+        "index": 0,
+        "beneficiaries": [{ "account_id": bob.account_id(), "amount": "600" }]
+    }).to_string().into_bytes();
+    assert!(!root.call(lockup.account_id(), "append_beneficiaries", &append_args, DEFAULT_GAS, 0).is_ok());
+    alice.call(lockup.account_id(), "append_beneficiaries", &append_args, DEFAULT_GAS, 0).assert_success();
+    // an account is appended once
+    assert!(!alice.call(lockup.account_id(), "append_beneficiaries", &append_args, DEFAULT_GAS, 0).is_ok());
+
+    // nothing can be claimed from a draft
+    let task: Value = view!(lockup.get_task(0)).unwrap_json_value();
+    assert_eq!(task["status"], "Draft");
+    let positions: Value = view!(lockup.get_positions(bob.account_id(), 0.into(), 10.into())).unwrap_json_value();
+    assert_eq!(positions[0]["should_claim"], "0");
+    assert!(!call!(bob, lockup.claim(ft.account_id(), 0, None, None), utils::CLAIM_DEPOSIT, DEFAULT_GAS).is_ok());
+    assert!(!call!(alice, lockup.activate_task(0)).is_ok());
+
+    // what is not allocated goes back to the treasury
+    assert!(!call!(bob, lockup.cancel_draft(0)).is_ok());
+    call!(alice, lockup.cancel_draft(0)).assert_success();
+    let treasury: U128 = view!(lockup.get_treasury_balance(alice.account_id(), ft.account_id())).unwrap_json();
+    assert_eq!(treasury.0, 400);
+    let task: Value = view!(lockup.get_task(0)).unwrap_json_value();
+    assert_eq!(task["amount"], "600");
+    assert!(!call!(bob, lockup.activate_task(0)).is_ok());
+    call!(alice, lockup.activate_task(0)).assert_success();
+    call!(bob, lockup.claim(ft.account_id(), 0, None, None), utils::CLAIM_DEPOSIT, DEFAULT_GAS).assert_success();
+    let balance: U128 = view!(ft.ft_balance_of(bob.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 600);
+
+    // an empty draft is completed
+    utils::create_task(&alice, &lockup, &ft, draft_args, 1000).assert_success();
+    call!(alice, lockup.cancel_draft(1)).assert_success();
+    let task: Value = view!(lockup.get_task(1)).unwrap_json_value();
+    assert_eq!(task["status"], "Completed");
+    let treasury: U128 = view!(lockup.get_treasury_balance(alice.account_id(), ft.account_id())).unwrap_json();
+    assert_eq!(treasury.0, 1400);
+    let token: Value = view!(lockup.get_token(ft.account_id())).unwrap_json_value();
+    assert_eq!(token["live_tasks"], "0");
+    assert_eq!(token["amount"], "600");
+}
+
+#[test]
+fn simulate_merkle_claims() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, 1100);
+    let bob = root.create_user("bob".to_string(), to_yocto("100"));
+    let carol = root.create_user("carol".to_string(), to_yocto("100"));
+    utils::register_user("ft", &bob);
+    utils::register_user("ft", &carol);
+
+    let bob_leaf = utils::merkle_leaf(&bob.account_id(), 600);
+    let carol_leaf = utils::merkle_leaf(&carol.account_id(), 400);
+    let merkle_root = utils::merkle_node(&bob_leaf, &carol_leaf);
+    let period: u64 = 100_000_000_000;
+    let start = utils::now(&root);
+    utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "token_id": ft.account_id(),
+            "account_list": [],
+            "start_time": start.to_string(),
+            "end_time": (start + 2 * period).to_string(),
+            "vesting_period": period.to_string(),
+            "amount": "1000",
+            "merkle_root": Base58CryptoHash::from(merkle_root)
+        }),
+        1000
+    ).assert_success();
+    let bob_proof = vec![Base58CryptoHash::from(carol_leaf)];
+    let carol_proof = vec![Base58CryptoHash::from(bob_leaf)];
+
+    utils::wait_until(&root, start + 3 * period / 2);
+    call!(bob, lockup.claim(ft.account_id(), 0, Some(600.into()), Some(bob_proof.clone())), utils::CLAIM_DEPOSIT, DEFAULT_GAS).assert_success();
+    let balance: U128 = view!(ft.ft_balance_of(bob.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 300);
+    // the proof only holds for the allocation and account of its leaf
+    assert!(!call!(carol, lockup.claim(ft.account_id(), 0, Some(500.into()), Some(carol_proof.clone())), utils::CLAIM_DEPOSIT, DEFAULT_GAS).is_ok());
+    assert!(!call!(carol, lockup.claim(ft.account_id(), 0, Some(600.into()), Some(bob_proof)), utils::CLAIM_DEPOSIT, DEFAULT_GAS).is_ok());
+    assert!(!call!(carol, lockup.claim(ft.account_id(), 0, None, None), utils::CLAIM_DEPOSIT, DEFAULT_GAS).is_ok());
+    // the claimer pays for its entry
+    assert!(!call!(carol, lockup.claim(ft.account_id(), 0, Some(400.into()), Some(carol_proof.clone())), 0, DEFAULT_GAS).is_ok());
+
+    // allocations are fixed by the root
+    let result = utils::create_task(&alice, &lockup, &ft, json!({ "index": 0, "account_id": carol.account_id(), "amount": "100" }), 100);
+    utils::assert_logged(&result, "E026");
+    let balance: U128 = view!(ft.ft_balance_of(alice.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 100);
+
+    // later claims don't need the proof again
+    utils::wait_until(&root, start + 2 * period);
+    call!(bob, lockup.claim(ft.account_id(), 0, None, None), utils::CLAIM_DEPOSIT, DEFAULT_GAS).assert_success();
+    let balance: U128 = view!(ft.ft_balance_of(bob.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 600);
+    call!(carol, lockup.claim(ft.account_id(), 0, Some(400.into()), Some(carol_proof)), utils::CLAIM_DEPOSIT, DEFAULT_GAS).assert_success();
+    let balance: U128 = view!(ft.ft_balance_of(carol.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 400);
+    let task: Value = view!(lockup.get_task(0)).unwrap_json_value();
+    assert_eq!(task["status"], "Completed");
+}
+
+#[test]
+fn simulate_next_unlock_after_long_extension() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, 1000);
+    let period: u64 = 10_000_000_000;
+    let start = utils::now(&root);
+    utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "token_id": ft.account_id(),
+            "account_list": ["bob"],
+            "start_time": start.to_string(),
+            "end_time": (start + 100 * period).to_string(),
+            "vesting_period": period.to_string(),
+            "amount": "1000"
+        }),
+        1000
+    ).assert_success();
+    utils::wait_until(&root, start + 5 * period / 2);
+    call!(alice, lockup.extend_task(0, (start + 1_000_000_000 * period).into())).assert_success();
+
+    // the other 980 vest over almost a billion periods, the first of them after a million
+    let claims: Value = view!(lockup.get_positions("bob".to_string(), 0.into(), 10.into())).unwrap_json_value();
+    assert_eq!(claims[0]["should_claim"], "20");
+    assert_eq!(claims[0]["next_unlock_time"], (start + 1_020_411 * period).to_string());
+    assert_eq!(claims[0]["next_unlock_amount"], "1");
+}
+
+#[test]
+fn simulate_abi_matches_contract() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, to_yocto("100"));
+    let abi: Value = near_sdk::serde_json::from_str(&std::fs::read_to_string("./contract/abi.json").unwrap()).unwrap();
+
+    let info: Value = view!(lockup.get_contract_info()).unwrap_json_value();
+    assert_eq!(info["schema_version"], abi["schema_version"]);
+    let token: Value = view!(lockup.get_token(ft.account_id())).unwrap_json_value();
+    let properties = abi["definitions"]["TokenInfo"]["properties"].as_object().unwrap();
+    for key in token.as_object().unwrap().keys() {
+        assert!(properties.contains_key(key), "{} missing from abi.json", key);
+    }
+    assert_eq!(token["live_tasks"], "0");
+
+    // every field is serialized, options as null
+    let assert_fields = |definition: &str, value: &Value| {
+        let mut keys: Vec<&String> = value.as_object().unwrap().keys().collect();
+        let mut properties: Vec<&String> = abi["definitions"][definition]["properties"].as_object().unwrap().keys().collect();
+        keys.sort();
+        properties.sort();
+        assert_eq!(keys, properties, "fields of {} don't match abi.json", definition);
+    };
+    let task_args = json!({
+        "token_id": ft.account_id(),
+        "account_list": ["bob"],
+        "start_time": "0",
+        "end_time": "1000",
+        "vesting_period": "100",
+        "amount": "1000"
+    });
+    utils::create_task(&alice, &lockup, &ft, task_args.clone(), 1000).assert_success();
+    let task: Value = view!(lockup.get_task(0)).unwrap_json_value();
+    assert_fields("TaskInfo", &task);
+    let positions: Value = view!(lockup.get_positions("bob".to_string(), 0.into(), 10.into())).unwrap_json_value();
+    assert_fields("Claim", &positions[0]);
+    let task_args: lockup::internal::TaskArgs = near_sdk::serde_json::from_value(task_args).unwrap();
+    assert_fields("TaskArgs", &near_sdk::serde_json::to_value(task_args).unwrap());
+
+    let mut methods: Vec<&str> = abi["methods"].as_array().unwrap().iter().map(|method| method["name"].as_str().unwrap()).collect();
+    let mut exports = utils::lockup_exports();
+    methods.sort();
+    exports.sort();
+    assert_eq!(methods, exports, "methods of abi.json don't match the contract");
+
+    // views take the arguments abi.json declares
+    for method in abi["methods"].as_array().unwrap().iter().filter(|method| method["kind"] == "view") {
+        let name = method["name"].as_str().unwrap();
+        let args = utils::sample_value(&abi, &method["params"]).to_string();
+        let result = root.view(lockup.account_id(), name, args.as_bytes());
+        if result.is_err() {
+            let err = result.unwrap_err().to_string();
+            assert!(!err.contains("deserialize"), "arguments of {} don't match abi.json: {}", name, err);
+        }
+    }
+}
+
+#[test]
+fn simulate_revoke_and_withdraw() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, 1001);
+    let carol = root.create_user("carol".to_string(), to_yocto("100"));
+
+    // starts far in the future, nothing is vested yet
+    utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "token_id": ft.account_id(),
+            "account_list": ["bob", "john"],
+            "start_time": "4000000000000000000",
+            "end_time": "4000000000000001000",
+            "vesting_period": "100",
+            "amount": "1001"
+        }),
+        1001
+    ).assert_success();
+    let treasury: U128 = view!(lockup.get_treasury_balance(alice.account_id(), ft.account_id())).unwrap_json();
+    assert_eq!(treasury.0, 1);
+    let tasks: Value = view!(lockup.get_tasks_by_creator(alice.account_id(), 0.into(), 10.into())).unwrap_json_value();
+    assert_eq!(tasks[0]["creator_id"], alice.account_id());
+
+    assert!(!call!(carol, lockup.revoke(0, "bob".to_string())).is_ok());
+    call!(alice, lockup.revoke(0, "bob".to_string())).assert_success();
+    let treasury: U128 = view!(lockup.get_treasury_balance(alice.account_id(), ft.account_id())).unwrap_json();
+    assert_eq!(treasury.0, 501);
+    let bob: Value = view!(lockup.get_positions("bob".to_string(), 0.into(), 10.into())).unwrap_json_value();
+    assert!(bob.as_array().unwrap().is_empty());
+
+    call!(alice, lockup.withdraw(ft.account_id(), None), deposit = 1).assert_success();
+    let balance: U128 = view!(ft.ft_balance_of(alice.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 501);
+    let balance: U128 = view!(ft.ft_balance_of(lockup.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 500);
+}
+
+#[test]
+fn simulate_factory_create_lockup() {
+    let (root, _lockup, _ft, alice) = utils::init(to_yocto("100000"));
+    let factory = utils::init_factory(&root);
+
+    call!(
+        alice,
+        factory.create("alice".to_string()),
+        to_yocto("10"),
+        DEFAULT_GAS
+    ).assert_success();
+    let instances: Vec<String> = view!(factory.get_instances(alice.account_id())).unwrap_json();
+    assert_eq!(instances, vec![format!("alice.{}", factory.account_id())]);
+    let info: Value = root.view(instances[0].clone(), "get_contract_info", b"{}").unwrap_json_value();
+    assert_eq!(info["owner_id"], alice.account_id());
+
+    // only newer code can be deployed, and only by the creator of the lockup
+    assert!(!call!(alice, factory.upgrade(instances[0].clone(), None)).is_ok());
+    assert!(!call!(root, factory.upgrade(instances[0].clone(), None)).is_ok());
+}
+
+#[test]
+fn simulate_factory_upgrade_lockup() {
+    let (root, _lockup, _ft, alice) = utils::init(to_yocto("100000"));
+    let factory = utils::init_factory(&root);
+    call!(
+        alice,
+        factory.create("alice".to_string()),
+        to_yocto("10"),
+        DEFAULT_GAS
+    ).assert_success();
+    let instance_id = format!("alice.{}", factory.account_id());
+
+    let old_hash: Value = view!(factory.get_code_hash()).unwrap_json_value();
+    let code_hash = utils::store_next_code(&root, &factory);
+    assert_ne!(code_hash, old_hash);
+    let upgraded: bool = call!(alice, factory.upgrade(instance_id.clone(), None)).unwrap_json();
+    assert!(upgraded);
+    let instance: Value = view!(factory.get_instance(instance_id.clone())).unwrap_json_value();
+    assert_eq!(instance["code_hash"], code_hash);
+    // the new code answers, with the state migrated by its `migrate`
+    let info: Value = root.view(instance_id.clone(), "get_contract_info", b"{}").unwrap_json_value();
+    assert_eq!(info["schema_version"], 1000);
+    assert_eq!(info["owner_id"], alice.account_id());
+
+    // the code it runs can't be deployed again
+    assert!(!call!(alice, factory.upgrade(instance_id, None)).is_ok());
+}
+
+#[test]
+fn simulate_acceptance() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, 2000);
+    let bob = root.create_user("bob".to_string(), to_yocto("100"));
+
+    let task_args = |acceptance_deadline: &str| json!({
+        "token_id": ft.account_id(),
+        "account_list": [bob.account_id()],
+        "start_time": "0",
+        "end_time": "1000",
+        "vesting_period": "100",
+        "amount": "1000",
+        "requires_acceptance": true,
+        "acceptance_deadline": acceptance_deadline
+    });
+    utils::create_task(&alice, &lockup, &ft, task_args("1"), 1000).assert_success();
+    utils::create_task(&alice, &lockup, &ft, task_args("4000000000000000000"), 1000).assert_success();
+
+    // the first deadline has passed
+    assert!(!call!(bob, lockup.accept(0)).is_ok());
+    assert!(!call!(bob, lockup.claim(ft.account_id(), 0, None, None)).is_ok());
+    call!(alice, lockup.reclaim(0, vec![bob.account_id()])).assert_success();
+    let treasury: U128 = view!(lockup.get_treasury_balance(alice.account_id(), ft.account_id())).unwrap_json();
+    assert_eq!(treasury.0, 1000);
+
+    assert!(!call!(alice, lockup.reclaim(1, vec![bob.account_id()])).is_ok());
+    call!(bob, lockup.accept(1)).assert_success();
+    let positions: Value = view!(lockup.get_positions(bob.account_id(), 0.into(), 10.into())).unwrap_json_value();
+    assert_eq!(positions.as_array().unwrap().len(), 1);
+    assert_eq!(positions[0]["index"], 1);
+    assert_eq!(positions[0]["accepted"], true);
+}
+
+#[test]
+fn simulate_staking() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, 2000);
+    let bob = root.create_user("bob".to_string(), to_yocto("100"));
+    utils::register_user("ft", &bob);
+    let pool = utils::init_staking_pool(&root, &ft);
+
+    let task_args = json!({
+        "token_id": ft.account_id(),
+        "account_list": [bob.account_id()],
+        "start_time": "0",
+        "end_time": "1000",
+        "vesting_period": "100",
+        "amount": "1000",
+        "staking_pool": pool.account_id()
+    });
+    utils::create_task(&alice, &lockup, &ft, task_args, 1000).assert_success();
+    let deposit: U128 = view!(pool.get_deposit(lockup.account_id(), "0".to_string())).unwrap_json();
+    assert_eq!(deposit.0, 1000);
+
+    // staking is only for tasks created with their accounts
+    let merkle_args = json!({
+        "token_id": ft.account_id(),
+        "account_list": [],
+        "start_time": "0",
+        "end_time": "1000",
+        "vesting_period": "100",
+        "amount": "1000",
+        "merkle_root": "11111111111111111111111111111111",
+        "staking_pool": pool.account_id()
+    });
+    utils::create_task(&alice, &lockup, &ft, merkle_args, 1000).assert_success();
+    let tasks: Value = view!(lockup.get_tasks_by_creator(alice.account_id(), 0.into(), 10.into())).unwrap_json_value();
+    assert_eq!(tasks.as_array().unwrap().len(), 1);
+    assert_eq!(tasks[0]["staking_pool"], pool.account_id());
+
+    call!(
+        root,
+        ft.ft_transfer_call(pool.valid_account_id(), 100.into(), None, format!("reward:{}:0", lockup.account_id())),
+        1,
+        DEFAULT_GAS
+    ).assert_success();
+    // returns of a stake only count from its pool
+    let root_balance: U128 = view!(ft.ft_balance_of(root.valid_account_id())).unwrap_json();
+    let result = call!(
+        root,
+        ft.ft_transfer_call(lockup.valid_account_id(), 500.into(), None, json!({ "stake_key": "0" }).to_string()),
+        1,
+        DEFAULT_GAS
+    );
+    utils::assert_logged(&result, "E027");
+    let balance: U128 = view!(ft.ft_balance_of(root.valid_account_id())).unwrap_json();
+    assert_eq!(balance, root_balance);
+    // the rewards are what the pool sends, not what it reports
+    call!(root, pool.set_report_extra(1000.into()), 0, DEFAULT_GAS).assert_success();
+    call!(bob, lockup.claim(ft.account_id(), 0, None, None), utils::CLAIM_DEPOSIT, DEFAULT_GAS).assert_success();
+    let balance: U128 = view!(ft.ft_balance_of(bob.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 1100);
+    let deposit: U128 = view!(pool.get_deposit(lockup.account_id(), "0".to_string())).unwrap_json();
+    assert_eq!(deposit.0, 0);
+    let task: Value = view!(lockup.get_task(0)).unwrap_json_value();
+    assert_eq!(task["status"], "Completed");
+}
+
+#[test]
+fn simulate_staking_late_return() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, 1000);
+    let bob = root.create_user("bob".to_string(), to_yocto("100"));
+    utils::register_user("ft", &bob);
+    let pool = utils::init_staking_pool(&root, &ft);
+    utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "token_id": ft.account_id(),
+            "account_list": [bob.account_id()],
+            "start_time": "0",
+            "end_time": "1000",
+            "vesting_period": "100",
+            "amount": "1000",
+            "staking_pool": pool.account_id()
+        }),
+        1000
+    ).assert_success();
+    call!(
+        root,
+        ft.ft_transfer_call(pool.valid_account_id(), 100.into(), None, format!("reward:{}:0", lockup.account_id())),
+        1,
+        DEFAULT_GAS
+    ).assert_success();
+
+    // the withdrawal resolves before the tokens arrive, the claim is given back
+    call!(root, pool.set_detach_returns(true), 0, DEFAULT_GAS).assert_success();
+    call!(bob, lockup.claim(ft.account_id(), 0, None, None), utils::CLAIM_DEPOSIT, DEFAULT_GAS).assert_success();
+    let balance: U128 = view!(ft.ft_balance_of(bob.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 0);
+    let claims: Value = view!(lockup.get_positions(bob.account_id(), 0.into(), 10.into())).unwrap_json_value();
+    assert_eq!(claims[0]["should_claim"], "1000");
+
+    // once arrived, the principal is paid out of the contract and the rest is reward
+    call!(bob, lockup.claim(ft.account_id(), 0, None, None), utils::CLAIM_DEPOSIT, DEFAULT_GAS).assert_success();
+    let balance: U128 = view!(ft.ft_balance_of(bob.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 1100);
+    let task: Value = view!(lockup.get_task(0)).unwrap_json_value();
+    assert_eq!(task["status"], "Completed");
+}
+
+#[test]
+fn simulate_voting_power() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, 1000);
+    let bob = root.create_user("bob".to_string(), to_yocto("100"));
+    let carol = root.create_user("carol".to_string(), to_yocto("100"));
+
+    utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "token_id": ft.account_id(),
+            "account_list": [bob.account_id(), carol.account_id()],
+            "start_time": "4000000000000000000",
+            "end_time": "4000000000000001000",
+            "vesting_period": "100",
+            "amount": "1000",
+            "voting": true
+        }),
+        1000
+    ).assert_success();
+    let power: U128 = view!(lockup.get_voting_power(bob.account_id(), ft.account_id())).unwrap_json();
+    assert_eq!(power.0, 500);
+    let before_delegation = root.borrow_runtime().current_block().block_timestamp;
+
+    // delegating adds storage, paid from the storage deposit
+    assert!(!call!(bob, lockup.delegate(ft.account_id(), Some(carol.account_id()))).is_ok());
+    call!(
+        bob,
+        lockup.storage_deposit(None, None),
+        to_yocto("1"),
+        DEFAULT_GAS
+    ).assert_success();
+    call!(bob, lockup.delegate(ft.account_id(), Some(carol.account_id()))).assert_success();
+    let delegate: String = view!(lockup.get_delegate(bob.account_id(), ft.account_id())).unwrap_json();
+    assert_eq!(delegate, carol.account_id());
+    let power: U128 = view!(lockup.get_voting_power(bob.account_id(), ft.account_id())).unwrap_json();
+    assert_eq!(power.0, 0);
+    let power: U128 = view!(lockup.get_voting_power(carol.account_id(), ft.account_id())).unwrap_json();
+    assert_eq!(power.0, 1000);
+    let power: U128 = view!(lockup.get_voting_power_at(carol.account_id(), ft.account_id(), before_delegation.into())).unwrap_json();
+    assert_eq!(power.0, 500);
+    let power: U128 = view!(lockup.get_voting_power_at(carol.account_id(), ft.account_id(), 0.into())).unwrap_json();
+    assert_eq!(power.0, 0);
+
+    // power taken back follows the delegation
+    call!(alice, lockup.revoke(0, bob.account_id())).assert_success();
+    let power: U128 = view!(lockup.get_voting_power(carol.account_id(), ft.account_id())).unwrap_json();
+    assert_eq!(power.0, 500);
+}
+
+#[test]
+fn simulate_claim_pays_checkpoint() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, 2000);
+    let bob = root.create_user("bob".to_string(), to_yocto("100"));
+    utils::register_user("ft", &bob);
+    let task_args = |voting: bool| json!({
+        "token_id": ft.account_id(),
+        "account_list": [bob.account_id()],
+        "start_time": "0",
+        "end_time": "1000",
+        "vesting_period": "100",
+        "amount": "1000",
+        "voting": voting
+    });
+    utils::create_task(&alice, &lockup, &ft, task_args(true), 1000).assert_success();
+    utils::create_task(&alice, &lockup, &ft, task_args(false), 1000).assert_success();
+
+    // the claimer pays for the checkpoint of its claim, not the contract
+    assert!(!call!(bob, lockup.claim(ft.account_id(), 0, None, None), 0, DEFAULT_GAS).is_ok());
+    // claims of a task without voting write no checkpoint and need no deposit
+    call!(bob, lockup.claim(ft.account_id(), 1, None, None), 0, DEFAULT_GAS).assert_success();
+    let balance: U128 = view!(ft.ft_balance_of(bob.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 1000);
+    call!(
+        bob,
+        lockup.storage_deposit(None, None),
+        to_yocto("1"),
+        DEFAULT_GAS
+    ).assert_success();
+    let storage: Value = view!(lockup.storage_balance_of(bob.valid_account_id())).unwrap_json_value();
+    let available_before: u128 = storage["available"].as_str().unwrap().parse().unwrap();
+    call!(bob, lockup.claim(ft.account_id(), 0, None, None), 0, DEFAULT_GAS).assert_success();
+    let balance: U128 = view!(ft.ft_balance_of(bob.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 2000);
+    let power: U128 = view!(lockup.get_voting_power(bob.account_id(), ft.account_id())).unwrap_json();
+    assert_eq!(power.0, 0);
+    let storage: Value = view!(lockup.storage_balance_of(bob.valid_account_id())).unwrap_json_value();
+    let available_after: u128 = storage["available"].as_str().unwrap().parse().unwrap();
+    assert!(available_after < available_before);
+    assert!(available_after > available_before - to_yocto("0.01"));
+}
+
+#[test]
+fn simulate_task_legs() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    let ft1 = utils::deploy_ft(&root, "ft1", to_yocto("100000"));
+    utils::register_user("ft1", &alice);
+    utils::setup_token(&root, &lockup, &ft, &alice, 1000);
+    utils::setup_token(&root, &lockup, &ft1, &alice, 300);
+    let bob = root.create_user("bob".to_string(), to_yocto("100"));
+    let carol = root.create_user("carol".to_string(), to_yocto("100"));
+    utils::register_user("ft", &bob);
+    utils::register_user("ft1", &bob);
+    utils::register_user("ft", &carol);
+
+    utils::create_task(
+        &alice,
+        &lockup,
+        &ft,
+        json!({
+            "token_id": ft.account_id(),
+            "account_list": [bob.account_id(), carol.account_id()],
+            "start_time": "0",
+            "end_time": "1000",
+            "vesting_period": "100",
+            "amount": "1000"
+        }),
+        1000
+    ).assert_success();
+    utils::create_task(&alice, &lockup, &ft1, json!({ "index": 0, "amount": "300" }), 300).assert_success();
+    let task: Value = view!(lockup.get_task(0)).unwrap_json_value();
+    assert_eq!(task["legs"][0]["token_id"], ft1.account_id());
+    let positions: Value = view!(lockup.get_positions(bob.account_id(), 0.into(), 10.into())).unwrap_json_value();
+    assert_eq!(positions[0]["legs"][0]["should_claim"], "150");
+
+    // one transfer per token
+    call!(bob, lockup.claim(ft.account_id(), 0, None, None), utils::CLAIM_DEPOSIT, DEFAULT_GAS).assert_success();
+    let balance: U128 = view!(ft.ft_balance_of(bob.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 500);
+    let balance: U128 = view!(ft1.ft_balance_of(bob.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 150);
+
+    // the leg carol is not registered for is kept until claimed again
+    call!(carol, lockup.claim(ft.account_id(), 0, None, None), utils::CLAIM_DEPOSIT, DEFAULT_GAS).assert_success();
+    let balance: U128 = view!(ft.ft_balance_of(carol.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 500);
+    let positions: Value = view!(lockup.get_positions(carol.account_id(), 0.into(), 10.into())).unwrap_json_value();
+    assert_eq!(positions[0]["amount_left"], "0");
+    assert_eq!(positions[0]["legs"][0]["should_claim"], "150");
+    // nor can the task be archived or the leg token removed until then
+    let task: Value = view!(lockup.get_task(0)).unwrap_json_value();
+    assert_eq!(task["status"], "Completed");
+    call!(alice, lockup.clear_accounts(0, vec![carol.account_id()])).assert_success();
+    assert!(!call!(alice, lockup.archive_task(0)).is_ok());
+    let token: Value = view!(lockup.get_token(ft1.account_id())).unwrap_json_value();
+    assert_eq!(token["live_tasks"], "1");
+    assert!(!call!(alice, lockup.remove_token(ft1.account_id())).is_ok());
+    utils::register_user("ft1", &carol);
+    call!(carol, lockup.claim(ft.account_id(), 0, None, None), utils::CLAIM_DEPOSIT, DEFAULT_GAS).assert_success();
+    let balance: U128 = view!(ft1.ft_balance_of(carol.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 150);
+    let positions: Value = view!(lockup.get_positions(carol.account_id(), 0.into(), 10.into())).unwrap_json_value();
+    assert!(positions.as_array().unwrap().is_empty());
+    let task: Value = view!(lockup.get_task(0)).unwrap_json_value();
+    assert_eq!(task["legs"][0]["amount_left"], "0");
+    let token: Value = view!(lockup.get_token(ft1.account_id())).unwrap_json_value();
+    assert_eq!(token["live_tasks"], "0");
+    call!(alice, lockup.archive_task(0)).assert_success();
+    let treasury: U128 = view!(lockup.get_treasury_balance(alice.account_id(), ft1.account_id())).unwrap_json();
+    assert_eq!(treasury.0, 0);
+}
+
+#[test]
+fn simulate_sweep_expired() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, 2000);
+    let bob = root.create_user("bob".to_string(), to_yocto("100"));
+    utils::register_user("ft", &bob);
+
+    let task_args = |claim_deadline: &str| json!({
+        "token_id": ft.account_id(),
+        "account_list": [bob.account_id()],
+        "start_time": "0",
+        "end_time": "1000",
+        "vesting_period": "100",
+        "amount": "1000",
+        "claim_deadline": claim_deadline,
+        "voting": true
+    });
+    // the deadline can't cut the schedule short
+    utils::create_task(&alice, &lockup, &ft, task_args("500"), 1000).assert_success();
+    let tasks: Value = view!(lockup.get_tasks_by_creator(alice.account_id(), 0.into(), 10.into())).unwrap_json_value();
+    assert!(tasks.as_array().unwrap().is_empty());
+
+    // long expired
+    utils::create_task(&alice, &lockup, &ft, task_args("2000"), 1000).assert_success();
+    let positions: Value = view!(lockup.get_positions(bob.account_id(), 0.into(), 10.into())).unwrap_json_value();
+    assert_eq!(positions[0]["should_claim"], "0");
+    assert!(!call!(bob, lockup.claim(ft.account_id(), 0, None, None)).is_ok());
+
+    // expired allocations stop counting for voting power before they are swept
+    assert!(!call!(alice, lockup.sweep_expired(0), deposit = 1).is_ok());
+    let power: U128 = view!(lockup.get_voting_power(bob.account_id(), ft.account_id())).unwrap_json();
+    assert_eq!(power.0, 1000);
+    call!(alice, lockup.clear_accounts(0, vec![bob.account_id()])).assert_success();
+    let power: U128 = view!(lockup.get_voting_power(bob.account_id(), ft.account_id())).unwrap_json();
+    assert_eq!(power.0, 0);
+
+    let balance_before: U128 = view!(ft.ft_balance_of(alice.valid_account_id())).unwrap_json();
+    assert!(!call!(bob, lockup.sweep_expired(0), deposit = 1).is_ok());
+    call!(alice, lockup.sweep_expired(0), deposit = 1).assert_success();
+    let balance_after: U128 = view!(ft.ft_balance_of(alice.valid_account_id())).unwrap_json();
+    assert_eq!(balance_after.0 - balance_before.0, 1000);
+    let task: Value = view!(lockup.get_task(0)).unwrap_json_value();
+    assert_eq!(task["status"], "Completed");
+    assert!(!call!(alice, lockup.sweep_expired(0), deposit = 1).is_ok());
+
+    // an extension can't run past the deadline
+    let task_args = json!({
+        "token_id": ft.account_id(),
+        "account_list": [bob.account_id()],
+        "start_time": "4000000000000000000",
+        "end_time": "4000000000000001000",
+        "vesting_period": "100",
+        "amount": "1000",
+        "claim_deadline": "4000000000000002000"
+    });
+    utils::create_task(&alice, &lockup, &ft, task_args, 1000).assert_success();
+    assert!(!call!(alice, lockup.extend_task(1, U64(4000000000000002000))).is_ok());
+    call!(alice, lockup.extend_task(1, U64(4000000000000001500))).assert_success();
+    let task: Value = view!(lockup.get_task(1)).unwrap_json_value();
+    assert_eq!(task["end_time"], "4000000000000001500");
+}
+
+#[test]
+fn simulate_tge_start() {
+    let (root, lockup, ft, alice) = utils::init(to_yocto("100000"));
+    utils::setup_token(&root, &lockup, &ft, &alice, 1000);
+    let bob = root.create_user("bob".to_string(), to_yocto("100"));
+    utils::register_user("ft", &bob);
+
+    // only the length of the schedule is known
+    let task_args = json!({
+        "token_id": ft.account_id(),
+        "account_list": [bob.account_id()],
+        "start_time": "0",
+        "end_time": "1000",
+        "vesting_period": "100",
+        "amount": "500",
+        "start_on_tge": true
+    });
+    utils::create_task(&alice, &lockup, &ft, task_args.clone(), 500).assert_success();
+    utils::create_task(&alice, &lockup, &ft, task_args, 500).assert_success();
+    let task: Value = view!(lockup.get_task(0)).unwrap_json_value();
+    assert_eq!(task["awaiting_tge"], true);
+    let positions: Value = view!(lockup.get_positions(bob.account_id(), 0.into(), 10.into())).unwrap_json_value();
+    assert_eq!(positions[0]["should_claim"], "0");
+    assert!(!call!(bob, lockup.claim(ft.account_id(), 0, None, None)).is_ok());
+
+    call!(alice, lockup.start_task(1)).assert_success();
+    let task: Value = view!(lockup.get_task(1)).unwrap_json_value();
+    assert_eq!(task["awaiting_tge"], false);
+    assert!(!call!(alice, lockup.start_task(1)).is_ok());
+
+    assert!(!call!(bob, lockup.set_tge(ft.account_id(), 1.into())).is_ok());
+    call!(alice, lockup.set_tge(ft.account_id(), 1.into())).assert_success();
+    let task: Value = view!(lockup.get_task(0)).unwrap_json_value();
+    assert_eq!(task["awaiting_tge"], false);
+    assert_eq!(task["start_time"], "1");
+    assert_eq!(task["end_time"], "1001");
+    call!(bob, lockup.claim(ft.account_id(), 0, None, None), utils::CLAIM_DEPOSIT, DEFAULT_GAS).assert_success();
+    let balance: U128 = view!(ft.ft_balance_of(bob.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 500);
+}
@@ -1,29 +1,44 @@
 pub use near_sdk::json_types::{Base64VecU8, ValidAccountId, WrappedDuration, U64};
 use near_sdk::serde_json::json;
 use near_sdk_sim::runtime::GenesisConfig;
-use near_sdk_sim::{call, view, deploy, init_simulator, ContractAccount, UserAccount};
+use near_sdk_sim::{call, view, deploy, init_simulator, ContractAccount, ExecutionResult, UserAccount};
 use lockup::LockupContract;
 use lockup::{*};
 use near_sdk::json_types::{U128};
 use near_sdk_sim::to_yocto;
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
 
 pub const DEFAULT_GAS: u64 = 300_000_000_000_000;
+/// Covers the storage a claim may pay for: its voting checkpoint, a merkle entry or the
+/// room of its legs. The rest is refunded.
+pub const CLAIM_DEPOSIT: u128 = 10_000_000_000_000_000_000_000;
 
 use fungible_token::ContractContract as FtContract;
+use reject_ft::ContractContract as RejectFtContract;
+use factory::FactoryContract;
+use staking_pool::ContractContract as StakingPoolContract;
 
 // Load in contract bytes at runtime
 near_sdk_sim::lazy_static_include::lazy_static_include_bytes! {
     FT_WASM_BYTES => "./out/fungible_token.wasm",
     LOCKUP_WASM_BYTES => "./out/main.wasm",
+    REJECT_FT_WASM_BYTES => "./out/reject_ft.wasm",
+    FACTORY_WASM_BYTES => "./out/factory.wasm",
+    STAKING_POOL_WASM_BYTES => "./out/staking_pool.wasm",
+    LOCKUP_NEXT_WASM_BYTES => "./out/lockup_next.wasm",
 }
 
 const FT_ID: &str = "ft";
 const LOCKUP_ID: &str = "lockup";
+const REJECT_FT_ID: &str = "reject_ft";
+const FACTORY_ID: &str = "factory";
+const STAKING_POOL_ID: &str = "staking_pool";
 
 // Register the given `user` with FT contract
-pub fn register_user(user: &near_sdk_sim::UserAccount) {
+pub fn register_user(token_id: &str, user: &near_sdk_sim::UserAccount) {
     user.call(
-        FT_ID.to_string(),
+        token_id.to_string(),
         "storage_deposit",
         &json!({
             "account_id": user.valid_account_id()
@@ -40,12 +55,32 @@ pub fn init(
     initial_balance: u128,
 ) -> (UserAccount, ContractAccount<LockupContract>, ContractAccount<FtContract>, UserAccount) {
     let root = init_simulator(None);
+    let ft = deploy_ft(&root, FT_ID, initial_balance);
+    let alice = root.create_user("alice".to_string(), to_yocto("10000"));
+    register_user(FT_ID, &alice);
+
+    let lockup = deploy!(
+        contract: LockupContract,
+        contract_id: LOCKUP_ID,
+        bytes: &LOCKUP_WASM_BYTES,
+        signer_account: root,
+        init_method: new(alice.account_id())
+    );
+
+    (root, lockup, ft, alice)
+}
+
+pub fn deploy_ft(
+    root: &UserAccount,
+    token_id: &str,
+    initial_balance: u128,
+) -> ContractAccount<FtContract> {
     // uses default values for deposit and gas
-    let ft = deploy!(
+    deploy!(
         // Contract Proxy
         contract: FtContract,
         // Contract account id
-        contract_id: FT_ID,
+        contract_id: token_id,
         // Bytes of contract
         bytes: &FT_WASM_BYTES,
         // User deploying the contract,
@@ -55,17 +90,229 @@ pub fn init(
             root.valid_account_id(),
             initial_balance.into()
         )
+    )
+}
+
+// Give `creator` a storage deposit with the lockup, which pays for its tokens and tasks
+pub fn register_creator(lockup: &ContractAccount<LockupContract>, creator: &UserAccount) {
+    call!(
+        creator,
+        lockup.storage_deposit(None, None),
+        to_yocto("1"),
+        DEFAULT_GAS
+    )
+    .assert_success();
+}
+
+// Give `owner` tokens to lock and register the token with the lockup
+pub fn setup_token(
+    root: &UserAccount,
+    lockup: &ContractAccount<LockupContract>,
+    ft: &ContractAccount<FtContract>,
+    owner: &UserAccount,
+    amount: u128,
+) {
+    call!(
+        root,
+        ft.ft_transfer(owner.valid_account_id(), amount.into(), None),
+        deposit = 1
+    )
+    .assert_success();
+    register_creator(lockup, owner);
+    call!(
+        owner,
+        lockup.add_token(ft.account_id()),
+        to_yocto("1"),
+        DEFAULT_GAS
+    )
+    .assert_success();
+}
+
+// Create a task by transferring `amount` of `ft` to the lockup with `task_args` as msg
+pub fn create_task(
+    owner: &UserAccount,
+    lockup: &ContractAccount<LockupContract>,
+    ft: &ContractAccount<FtContract>,
+    task_args: near_sdk::serde_json::Value,
+    amount: u128,
+) -> ExecutionResult {
+    call!(
+        owner,
+        ft.ft_transfer_call(lockup.valid_account_id(), amount.into(), None, task_args.to_string()),
+        1,
+        DEFAULT_GAS
+    )
+}
+
+// Assert a receipt of `result` logged an error starting with `code`
+pub fn assert_logged(result: &ExecutionResult, code: &str) {
+    assert!(
+        result.promise_results().iter().any(|outcome| {
+            outcome.as_ref().map_or(false, |outcome| outcome.logs().iter().any(|log| log.starts_with(code)))
+        }),
+        "{} not logged",
+        code
     );
-    let alice = root.create_user("alice".to_string(), to_yocto("10000"));
-    register_user(&alice);
+}
 
-    let lockup = deploy!(
-        contract: LockupContract,
-        contract_id: LOCKUP_ID,
-        bytes: &LOCKUP_WASM_BYTES,
+// Deploy a token that rejects every storage registration
+pub fn init_reject_ft(root: &UserAccount) -> ContractAccount<RejectFtContract> {
+    deploy!(
+        contract: RejectFtContract,
+        contract_id: REJECT_FT_ID,
+        bytes: &REJECT_FT_WASM_BYTES,
+        signer_account: root
+    )
+}
+
+// Deploy the factory with the lockup code stored
+pub fn init_factory(root: &UserAccount) -> ContractAccount<FactoryContract> {
+    let factory = deploy!(
+        contract: FactoryContract,
+        contract_id: FACTORY_ID,
+        bytes: &FACTORY_WASM_BYTES,
         signer_account: root,
-        init_method: new(alice.account_id())
+        init_method: new(root.account_id())
     );
+    root.call(
+        factory.account_id(),
+        "store_code",
+        &LOCKUP_WASM_BYTES,
+        DEFAULT_GAS,
+        to_yocto("10"),
+    )
+    .assert_success();
+    factory
+}
 
-    (root, lockup, ft, alice)
-}
\ No newline at end of file
+// Store a later version of the lockup code in the factory, returning its hash
+pub fn store_next_code(root: &UserAccount, factory: &ContractAccount<FactoryContract>) -> near_sdk::serde_json::Value {
+    let result = root.call(
+        factory.account_id(),
+        "store_code",
+        &LOCKUP_NEXT_WASM_BYTES,
+        DEFAULT_GAS,
+        to_yocto("10"),
+    );
+    result.assert_success();
+    result.unwrap_json_value()
+}
+
+// Deploy a staking pool of `ft` and register it with the token
+pub fn init_staking_pool(root: &UserAccount, ft: &ContractAccount<FtContract>) -> ContractAccount<StakingPoolContract> {
+    let pool = deploy!(
+        contract: StakingPoolContract,
+        contract_id: STAKING_POOL_ID,
+        bytes: &STAKING_POOL_WASM_BYTES,
+        signer_account: root,
+        init_method: new(ft.valid_account_id())
+    );
+    root.call(
+        ft.account_id(),
+        "storage_deposit",
+        &json!({
+            "account_id": pool.valid_account_id()
+        })
+        .to_string()
+        .into_bytes(),
+        near_sdk_sim::DEFAULT_GAS / 2,
+        near_sdk::env::storage_byte_cost() * 125,
+    )
+    .assert_success();
+    pool
+}
+
+// Merkle leaf of the allocation of `account_id`, hashed like the lockup does
+pub fn merkle_leaf(account_id: &str, amount: u128) -> near_sdk::CryptoHash {
+    Sha256::digest(&lockup::merkle::leaf_data(&account_id.to_string(), amount)).as_slice().try_into().unwrap()
+}
+
+// Parent of two merkle nodes
+pub fn merkle_node(left: &near_sdk::CryptoHash, right: &near_sdk::CryptoHash) -> near_sdk::CryptoHash {
+    Sha256::digest(&lockup::merkle::node_data(left, right)).as_slice().try_into().unwrap()
+}
+
+// Timestamp of the current block
+pub fn now(root: &UserAccount) -> u64 {
+    root.borrow_runtime().current_block().block_timestamp
+}
+
+// Produce blocks until the block timestamp reaches `timestamp`
+pub fn wait_until(root: &UserAccount, timestamp: u64) {
+    let mut runtime = root.borrow_runtime_mut();
+    while runtime.current_block().block_timestamp < timestamp {
+        runtime.produce_block().unwrap();
+    }
+}
+
+// Names of the functions the lockup wasm exports, read from its export section
+// Value of the JSON `schema` of abi.json, with only the required fields of objects
+pub fn sample_value(abi: &near_sdk::serde_json::Value, schema: &near_sdk::serde_json::Value) -> near_sdk::serde_json::Value {
+    if let Some(reference) = schema["$ref"].as_str() {
+        let name = reference.trim_start_matches("#/definitions/");
+        return match name {
+            "U64" | "U128" => json!("0"),
+            "AccountId" => json!("alice"),
+            "Base58CryptoHash" => json!("11111111111111111111111111111111"),
+            _ => sample_value(abi, &abi["definitions"][name]),
+        };
+    }
+    if let Some(options) = schema["anyOf"].as_array() {
+        return sample_value(abi, &options[0]);
+    }
+    match schema["type"].as_str() {
+        Some("integer") => json!(0),
+        Some("boolean") => json!(false),
+        Some("string") => json!(""),
+        Some("array") => json!([]),
+        _ => {
+            let mut value = near_sdk::serde_json::Map::new();
+            for name in schema["required"].as_array().into_iter().flatten() {
+                let name = name.as_str().unwrap();
+                value.insert(name.to_string(), sample_value(abi, &schema["properties"][name]));
+            }
+            value.into()
+        }
+    }
+}
+
+pub fn lockup_exports() -> Vec<String> {
+    let bytes: &[u8] = &LOCKUP_WASM_BYTES;
+    // after the magic number and version, sections are an id and a size
+    let mut pos = 8;
+    while pos < bytes.len() {
+        let id = bytes[pos];
+        pos += 1;
+        let size = read_leb128(bytes, &mut pos) as usize;
+        if id == 7 {
+            let mut names = vec![];
+            for _ in 0..read_leb128(bytes, &mut pos) {
+                let len = read_leb128(bytes, &mut pos) as usize;
+                let name = String::from_utf8(bytes[pos..pos + len].to_vec()).unwrap();
+                let kind = bytes[pos + len];
+                pos += len + 1;
+                read_leb128(bytes, &mut pos);
+                if kind == 0 {
+                    names.push(name);
+                }
+            }
+            return names;
+        }
+        pos += size;
+    }
+    vec![]
+}
+
+fn read_leb128(bytes: &[u8], pos: &mut usize) -> u64 {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}